@@ -0,0 +1,5 @@
+-- Profile pages show when a user joined, so we start recording it. Users that
+-- signed up before this migration get the time the migration was run.
+-- Our other timestamps are stored in UTC so we do the same here.
+ALTER TABLE users
+    ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc');
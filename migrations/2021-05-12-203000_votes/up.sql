@@ -0,0 +1,23 @@
+-- Votes let users push a post up or down. Each row is one user's vote on one
+-- post, with a value of 1 for an up vote and -1 for a down vote. The UNIQUE
+-- constraint makes sure a user can only ever have one vote per post, changing
+-- their mind updates the existing row instead of adding another one.
+CREATE TABLE votes
+(
+    id         SERIAL PRIMARY KEY,
+    post_id    INT       NOT NULL,
+    user_id    INT       NOT NULL,
+    value      SMALLINT  NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+
+    UNIQUE (post_id, user_id),
+    CHECK (value IN (-1, 1)),
+
+    CONSTRAINT fk_post
+        FOREIGN KEY (post_id)
+            REFERENCES posts (id),
+
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users (id)
+);
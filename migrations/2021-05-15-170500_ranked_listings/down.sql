@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX posts_top_idx;
+DROP INDEX posts_new_idx;
+DROP INDEX posts_hot_idx;
+DROP FUNCTION hot(INT, TIMESTAMP);
+DROP TRIGGER votes_update_post_score ON votes;
+DROP FUNCTION update_post_score();
+ALTER TABLE posts DROP COLUMN score;
@@ -0,0 +1,44 @@
+-- The front page can be sorted by hot, new and top. Adding up the votes for
+-- every post on every page view doesn't scale, so we keep a running score on
+-- the post itself. A trigger on the votes table keeps it up to date whenever a
+-- vote is added, changed or removed.
+ALTER TABLE posts
+    ADD COLUMN score INT NOT NULL DEFAULT 0;
+
+UPDATE posts
+SET score = COALESCE((SELECT SUM(value) FROM votes WHERE votes.post_id = posts.id), 0);
+
+CREATE FUNCTION update_post_score() RETURNS TRIGGER AS
+$$
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') THEN
+        UPDATE posts SET score = score - OLD.value WHERE id = OLD.post_id;
+    END IF;
+    IF TG_OP IN ('INSERT', 'UPDATE') THEN
+        UPDATE posts SET score = score + NEW.value WHERE id = NEW.post_id;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER votes_update_post_score
+    AFTER INSERT OR UPDATE OR DELETE
+    ON votes
+    FOR EACH ROW
+EXECUTE PROCEDURE update_post_score();
+
+-- The hot ranking works like the link aggregators do it. The score counts on
+-- a log scale, so the first 10 votes count as much as the next 90, and every
+-- 12.5 hours (45000 seconds) of age is worth the same as a factor of 10 in
+-- votes. Newer posts always start out ahead, so old posts drift down the page
+-- instead of sitting on top forever.
+-- The function is IMMUTABLE so that we can build an index on it.
+CREATE FUNCTION hot(score INT, created_at TIMESTAMP) RETURNS DOUBLE PRECISION AS
+$$
+SELECT SIGN(score)::DOUBLE PRECISION * LOG(GREATEST(ABS(score), 1)::DOUBLE PRECISION)
+           + EXTRACT(EPOCH FROM (created_at - TIMESTAMP '2021-01-01'))::DOUBLE PRECISION / 45000.0
+$$ LANGUAGE SQL IMMUTABLE;
+
+CREATE INDEX posts_hot_idx ON posts (hot(score, created_at) DESC, id DESC);
+CREATE INDEX posts_new_idx ON posts (created_at DESC, id DESC);
+CREATE INDEX posts_top_idx ON posts (score DESC, created_at DESC, id DESC);
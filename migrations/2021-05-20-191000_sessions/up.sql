@@ -0,0 +1,21 @@
+-- A session is one browser a user logged in from. The login cookie holds the
+-- random token instead of the username, so a session can be ended from the
+-- server side by deleting its row. We keep the user agent and address it was
+-- created from so users can tell their sessions apart on the account page.
+CREATE TABLE sessions
+(
+    id           SERIAL PRIMARY KEY,
+    token        VARCHAR   NOT NULL UNIQUE,
+    user_id      INT       NOT NULL,
+    user_agent   VARCHAR,
+    ip           VARCHAR,
+    created_at   TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    last_seen_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users (id)
+            ON DELETE CASCADE
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE posts DROP CONSTRAINT posts_link_or_body;
+ALTER TABLE posts DROP COLUMN body;
@@ -0,0 +1,6 @@
+-- Text posts. A post can have a link, a body of text, or both, but it has to
+-- have at least one of them.
+ALTER TABLE posts ADD COLUMN body TEXT;
+
+ALTER TABLE posts ADD CONSTRAINT posts_link_or_body
+    CHECK (link IS NOT NULL OR body IS NOT NULL);
@@ -0,0 +1,9 @@
+-- Authors can edit and delete their posts and comments. edited_at is when
+-- something was last changed, so we can mark it as edited. Deleting only sets
+-- deleted_at: the row stays where it is so the replies underneath it still
+-- have a thread to hang off, and we show [deleted] in its place.
+ALTER TABLE posts ADD COLUMN edited_at TIMESTAMP;
+ALTER TABLE posts ADD COLUMN deleted_at TIMESTAMP;
+
+ALTER TABLE comments ADD COLUMN edited_at TIMESTAMP;
+ALTER TABLE comments ADD COLUMN deleted_at TIMESTAMP;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE comments DROP COLUMN comment_html;
+ALTER TABLE posts DROP COLUMN body_html;
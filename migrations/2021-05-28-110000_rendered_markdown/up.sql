@@ -0,0 +1,6 @@
+-- Comments and post bodies are written in markdown. The HTML we render from
+-- them is kept next to the source so a page view doesn't parse every comment
+-- again. A NULL means it hasn't been rendered yet, the server fills those in
+-- when it starts, so setting these columns back to NULL re-renders everything.
+ALTER TABLE posts ADD COLUMN body_html TEXT;
+ALTER TABLE comments ADD COLUMN comment_html TEXT;
@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+DROP TRIGGER comments_search_vector_update ON comments;
+DROP TRIGGER posts_search_vector_update ON posts;
+
+ALTER TABLE comments DROP COLUMN search_vector;
+ALTER TABLE posts DROP COLUMN search_vector;
+
+DROP FUNCTION comments_search_vector();
+DROP FUNCTION posts_search_vector();
+DROP FUNCTION link_domain(TEXT);
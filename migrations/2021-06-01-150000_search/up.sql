@@ -0,0 +1,61 @@
+-- Full text search over posts and comments. Each row keeps its words in a
+-- tsvector column, with a GIN index so postgres can find the matching rows
+-- without reading every one. Triggers fill the column in whenever the text
+-- changes, so it never goes stale.
+--
+-- The columns aren't in schema.rs: diesel has no type for tsvector, and only
+-- the search queries in search.rs, which are written in SQL, ever use them.
+
+-- The host part of a link, without a leading www. Both the whole host and its
+-- parts go in, so searching for example finds links to news.example.com.
+CREATE FUNCTION link_domain(link TEXT) RETURNS TEXT AS
+$$
+SELECT substring(lower(link) FROM '^[a-z]+://(?:www\.)?([^/:?#]+)')
+$$ LANGUAGE SQL IMMUTABLE;
+
+-- Titles count for more than the link's domain, which counts for more than
+-- the body, when ranking the results.
+CREATE FUNCTION posts_search_vector() RETURNS TRIGGER AS
+$$
+DECLARE
+    domain TEXT := link_domain(NEW.link);
+BEGIN
+    NEW.search_vector :=
+            setweight(to_tsvector('english', NEW.title), 'A') ||
+            setweight(to_tsvector('english', coalesce(domain || ' ' || replace(domain, '.', ' '), '')), 'B') ||
+            setweight(to_tsvector('english', coalesce(NEW.body, '')), 'C');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION comments_search_vector() RETURNS TRIGGER AS
+$$
+BEGIN
+    NEW.search_vector := to_tsvector('english', NEW.comment);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE posts ADD COLUMN search_vector TSVECTOR;
+ALTER TABLE comments ADD COLUMN search_vector TSVECTOR;
+
+-- Only changes to the text itself, a vote changing the score shouldn't have to
+-- index the post again.
+CREATE TRIGGER posts_search_vector_update
+    BEFORE INSERT OR UPDATE OF title, link, body
+    ON posts
+    FOR EACH ROW
+EXECUTE PROCEDURE posts_search_vector();
+
+CREATE TRIGGER comments_search_vector_update
+    BEFORE INSERT OR UPDATE OF comment
+    ON comments
+    FOR EACH ROW
+EXECUTE PROCEDURE comments_search_vector();
+
+-- Fills in the rows we already have, through the triggers.
+UPDATE posts SET title = title;
+UPDATE comments SET comment = comment;
+
+CREATE INDEX posts_search_idx ON posts USING GIN (search_vector);
+CREATE INDEX comments_search_idx ON comments USING GIN (search_vector);
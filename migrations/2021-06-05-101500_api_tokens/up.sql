@@ -0,0 +1,23 @@
+-- Personal API tokens, so scripts and bots can use the API without knowing
+-- anyone's password. A token only allows what its scopes say: read, post
+-- and/or comment. We only keep a SHA-256 hash of the token, which is enough
+-- to look it up but no use to anyone who gets hold of the table. The prefix
+-- is the start of the token, so the user can tell their tokens apart.
+CREATE TABLE api_tokens
+(
+    id           SERIAL PRIMARY KEY,
+    user_id      INT          NOT NULL,
+    name         VARCHAR      NOT NULL,
+    token_hash   VARCHAR      NOT NULL UNIQUE,
+    prefix       VARCHAR      NOT NULL,
+    scopes       TEXT[]       NOT NULL,
+    created_at   TIMESTAMP    NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    last_used_at TIMESTAMP,
+
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users (id)
+            ON DELETE CASCADE
+);
+
+CREATE INDEX api_tokens_user_id_idx ON api_tokens (user_id);
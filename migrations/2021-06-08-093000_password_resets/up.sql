@@ -0,0 +1,19 @@
+-- One-time tokens for resetting a forgotten password. The token goes out in
+-- an email and only its SHA-256 hash is kept here, the same as API tokens. A
+-- token is deleted as soon as it has been used, and can't be used after it
+-- expires.
+CREATE TABLE password_resets
+(
+    id         SERIAL PRIMARY KEY,
+    user_id    INT       NOT NULL,
+    token_hash VARCHAR   NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    expires_at TIMESTAMP NOT NULL,
+
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users (id)
+            ON DELETE CASCADE
+);
+
+CREATE INDEX password_resets_user_id_idx ON password_resets (user_id);
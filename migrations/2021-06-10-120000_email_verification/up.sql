@@ -0,0 +1,6 @@
+-- When the user proved the email address is theirs by following the link we
+-- sent it, or NULL if they haven't yet. Everyone who signed up before we
+-- started checking counts as verified from when they signed up.
+ALTER TABLE users ADD COLUMN email_verified_at TIMESTAMP;
+
+UPDATE users SET email_verified_at = created_at;
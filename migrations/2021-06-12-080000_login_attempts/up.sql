@@ -0,0 +1,11 @@
+-- Failed logins, counted per username and per address so we can slow down
+-- anyone guessing passwords. The key is "user:<username>" or "ip:<address>".
+-- Only used when LOGIN_THROTTLE_STORE=postgres, see throttle.rs. Rows are
+-- forgotten once they are older than the failure window.
+CREATE TABLE login_attempts
+(
+    key             VARCHAR PRIMARY KEY,
+    failures        INT       NOT NULL,
+    last_failure_at TIMESTAMP NOT NULL,
+    blocked_until   TIMESTAMP
+);
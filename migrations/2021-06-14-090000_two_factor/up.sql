@@ -0,0 +1,26 @@
+-- Optional two-factor authentication. totp_secret is the base32 secret shared
+-- with the user's authenticator app. It's filled in as soon as they start
+-- setting it up, but only counts once totp_enabled_at is set, after they've
+-- typed in a code to show their app has it. totp_last_step is the 30 second
+-- step of the last code used to log in, so the same code can't be used again.
+ALTER TABLE users ADD COLUMN totp_secret VARCHAR;
+ALTER TABLE users ADD COLUMN totp_enabled_at TIMESTAMP;
+ALTER TABLE users ADD COLUMN totp_last_step BIGINT;
+
+-- Single use codes for logging in without the authenticator app. Like API
+-- tokens only the SHA-256 hash is kept, and a code is deleted once it has
+-- been used.
+CREATE TABLE recovery_codes
+(
+    id         SERIAL PRIMARY KEY,
+    user_id    INT       NOT NULL,
+    code_hash  VARCHAR   NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users (id)
+            ON DELETE CASCADE
+);
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
@@ -0,0 +1,17 @@
+-- The names users had before they renamed their account, so links to their
+-- old profile keep working. An old name stays with the user it belonged to
+-- and nobody else can sign up with it or take it, otherwise their profile
+-- links would lead to someone else. Renaming back to it frees it again.
+CREATE TABLE username_redirects
+(
+    username   VARCHAR   PRIMARY KEY,
+    user_id    INT       NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users (id)
+            ON DELETE CASCADE
+);
+
+CREATE INDEX username_redirects_user_id_idx ON username_redirects (user_id);
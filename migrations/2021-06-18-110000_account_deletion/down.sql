@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+-- The [deleted] user can only go if no posts or comments were given to it.
+DELETE FROM users WHERE username = '[deleted]';
+
+DROP TABLE account_deletions;
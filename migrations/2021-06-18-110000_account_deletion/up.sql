@@ -0,0 +1,28 @@
+-- Accounts their owners asked us to delete. Nothing happens straight away,
+-- the account is only deleted once delete_at has passed, and until then the
+-- user can change their mind. mode says what happens to their posts and
+-- comments: 'anonymize' keeps them under the [deleted] user below, 'erase'
+-- deletes them.
+CREATE TABLE account_deletions
+(
+    user_id      INT       PRIMARY KEY,
+    mode         VARCHAR   NOT NULL,
+    requested_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    delete_at    TIMESTAMP NOT NULL,
+
+    CHECK (mode IN ('anonymize', 'erase')),
+
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users (id)
+            ON DELETE CASCADE
+);
+
+CREATE INDEX account_deletions_delete_at_idx ON account_deletions (delete_at);
+
+-- The posts and comments of deleted accounts that are kept belong to this
+-- user instead, so posts.author and comments.user_id still point somewhere.
+-- Nobody can log in as it: [deleted] isn't a username anyone can sign up
+-- with, and an empty password hash never matches.
+INSERT INTO users (username, email, password, email_verified_at)
+VALUES ('[deleted]', 'deleted@invalid', '', NOW() AT TIME ZONE 'utc');
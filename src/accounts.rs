@@ -0,0 +1,255 @@
+// What we do for users who want a copy of their data, or want it gone.
+//
+// The export is everything we keep about a user, as one JSON file: their
+// profile, posts, comments, votes, where they're logged in and their API
+// tokens. Password hashes, session tokens and two-factor secrets are left
+// out, they're of no use to anyone outside and dangerous if the file leaks.
+//
+// Deleting an account doesn't happen straight away. Asking for it puts a row
+// in account_deletions with the time it's due, ACCOUNT_DELETION_DELAY from
+// now, and until then the user can change their mind from their settings.
+// delete_due() is run in the background every so often to carry out the ones
+// whose time has come.
+//
+// Posts and comments are part of other people's discussions, so there's a
+// choice of what happens to them:
+//
+// * anonymize keeps them as they are but hands them over to the [deleted]
+//   user, so nothing links them to the account any more.
+// * erase deletes them. Anything that other people's comments hang off can't
+//   be deleted without taking those with it, so those are emptied out and
+//   handed to [deleted] instead, the same as deleted posts and comments look.
+//
+// Either way the user's votes are deleted, which takes them off the scores.
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::Integer;
+use serde::Serialize;
+use super::models::{AccountDeletion, ApiToken, Comment, Post, Session, User, Vote};
+use super::schema::{account_deletions, api_tokens, comments, posts, sessions, username_redirects,
+                    users, votes};
+
+// How many seconds apart the background thread in main() looks for accounts
+// that are due to be deleted.
+pub const CHECK_INTERVAL: u64 = 60;
+
+// Who the kept posts and comments of deleted accounts belong to. Made by the
+// account_deletion migration.
+pub const GHOST_USERNAME: &str = "[deleted]";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Anonymize,
+    Erase,
+}
+
+impl Mode {
+    pub fn parse(name: &str) -> Option<Mode> {
+        match name {
+            "anonymize" => Some(Mode::Anonymize),
+            "erase" => Some(Mode::Erase),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Anonymize => "anonymize",
+            Mode::Erase => "erase",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Export {
+    pub exported_at: NaiveDateTime,
+    pub profile: ProfileExport,
+    pub former_usernames: Vec<String>,
+    pub posts: Vec<Post>,
+    pub comments: Vec<Comment>,
+    pub votes: Vec<Vote>,
+    pub sessions: Vec<Session>,
+    pub api_tokens: Vec<ApiToken>,
+    pub pending_deletion: Option<AccountDeletion>,
+}
+
+#[derive(Serialize)]
+pub struct ProfileExport {
+    pub id: i32,
+    pub username: String,
+    pub email: String,
+    pub created_at: NaiveDateTime,
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub two_factor_enabled_at: Option<NaiveDateTime>,
+}
+
+// Everything we have on the user, oldest first.
+pub fn export(connection: &PgConnection, user: &User) -> QueryResult<Export> {
+    Ok(Export {
+        exported_at: Utc::now().naive_utc(),
+        profile: ProfileExport {
+            id: user.id,
+            username: user.username.clone(),
+            email: user.email.clone(),
+            created_at: user.created_at,
+            email_verified_at: user.email_verified_at,
+            two_factor_enabled_at: user.totp_enabled_at,
+        },
+        former_usernames: username_redirects::table
+            .select(username_redirects::username)
+            .filter(username_redirects::user_id.eq(user.id))
+            .order(username_redirects::created_at)
+            .load(connection)?,
+        posts: posts::table
+            .filter(posts::author.eq(user.id))
+            .order(posts::created_at)
+            .load(connection)?,
+        comments: comments::table
+            .filter(comments::user_id.eq(user.id))
+            .order(comments::created_at)
+            .load(connection)?,
+        votes: votes::table
+            .filter(votes::user_id.eq(user.id))
+            .order(votes::created_at)
+            .load(connection)?,
+        sessions: sessions::table
+            .filter(sessions::user_id.eq(user.id))
+            .order(sessions::created_at)
+            .load(connection)?,
+        api_tokens: api_tokens::table
+            .filter(api_tokens::user_id.eq(user.id))
+            .order(api_tokens::created_at)
+            .load(connection)?,
+        pending_deletion: pending_deletion(connection, user.id)?,
+    })
+}
+
+pub fn pending_deletion(connection: &PgConnection, user_id: i32) -> QueryResult<Option<AccountDeletion>> {
+    account_deletions::table.find(user_id).first(connection).optional()
+}
+
+// Schedules the account for deletion after the delay. Asking again starts the
+// wait over with the new mode.
+pub fn request_deletion(connection: &PgConnection,
+                        user_id: i32,
+                        mode: Mode,
+                        delay: i64) -> QueryResult<AccountDeletion> {
+    let now = Utc::now().naive_utc();
+    let deletion = AccountDeletion {
+        user_id,
+        mode: mode.name().to_string(),
+        requested_at: now,
+        delete_at: now + chrono::Duration::seconds(delay),
+    };
+
+    diesel::insert_into(account_deletions::table)
+        .values(&deletion)
+        .on_conflict(account_deletions::user_id)
+        .do_update()
+        .set((account_deletions::mode.eq(&deletion.mode),
+              account_deletions::requested_at.eq(deletion.requested_at),
+              account_deletions::delete_at.eq(deletion.delete_at)))
+        .get_result(connection)
+}
+
+pub fn cancel_deletion(connection: &PgConnection, user_id: i32) -> QueryResult<usize> {
+    diesel::delete(account_deletions::table.find(user_id)).execute(connection)
+}
+
+// Deletes every account whose time is up, and says how many that was. Each
+// one is deleted in its own transaction, so one that fails doesn't hold up the
+// rest.
+pub fn delete_due(connection: &PgConnection, now: NaiveDateTime) -> QueryResult<usize> {
+    let due: Vec<AccountDeletion> = account_deletions::table
+        .filter(account_deletions::delete_at.le(now))
+        .load(connection)?;
+
+    let mut deleted = 0;
+    for deletion in due {
+        // The CHECK on the column means this is always one of the two.
+        let mode = Mode::parse(&deletion.mode).unwrap_or(Mode::Erase);
+        match delete_account(connection, deletion.user_id, mode) {
+            Ok(()) => deleted += 1,
+            Err(e) => log::error!("Couldn't delete account {}: {}", deletion.user_id, e),
+        }
+    }
+    Ok(deleted)
+}
+
+// Deletes the account for good. Everything else that belongs to the user, like
+// their sessions and tokens, goes with the users row by ON DELETE CASCADE.
+pub fn delete_account(connection: &PgConnection, user_id: i32, mode: Mode) -> QueryResult<()> {
+    connection.transaction(|| {
+        let ghost: i32 = users::table
+            .select(users::id)
+            .filter(users::username.eq(GHOST_USERNAME))
+            .first(connection)?;
+
+        diesel::delete(votes::table.filter(votes::user_id.eq(user_id))).execute(connection)?;
+
+        match mode {
+            Mode::Anonymize => {
+                diesel::update(comments::table.filter(comments::user_id.eq(user_id)))
+                    .set(comments::user_id.eq(ghost))
+                    .execute(connection)?;
+                diesel::update(posts::table.filter(posts::author.eq(user_id)))
+                    .set(posts::author.eq(ghost))
+                    .execute(connection)?;
+            }
+            Mode::Erase => erase_content(connection, user_id, ghost)?,
+        }
+
+        diesel::delete(users::table.find(user_id)).execute(connection)?;
+        Ok(())
+    })
+}
+
+fn erase_content(connection: &PgConnection, user_id: i32, ghost: i32) -> QueryResult<()> {
+    let now = Utc::now().naive_utc();
+
+    // Comments without replies can go. That can leave one of the user's
+    // comments that only they had replied to without replies as well, so we go
+    // round again until nothing more goes.
+    while diesel::sql_query(
+        "DELETE FROM comments c
+         WHERE c.user_id = $1
+           AND NOT EXISTS (SELECT 1 FROM comments r WHERE r.parent_comment_id = c.id)")
+        .bind::<Integer, _>(user_id)
+        .execute(connection)? > 0 {}
+
+    // The rest have other people's replies under them. An empty comment_html
+    // keeps markdown::backfill from rendering them again.
+    diesel::update(comments::table.filter(comments::user_id.eq(user_id)))
+        .set((comments::comment.eq(""),
+              comments::comment_html.eq(""),
+              comments::deleted_at.eq(now),
+              comments::user_id.eq(ghost)))
+        .execute(connection)?;
+
+    // Posts nobody commented on can go too, along with other people's votes on
+    // them.
+    diesel::sql_query(
+        "DELETE FROM votes
+         WHERE post_id IN (SELECT p.id FROM posts p
+                           WHERE p.author = $1
+                             AND NOT EXISTS (SELECT 1 FROM comments c WHERE c.post_id = p.id))")
+        .bind::<Integer, _>(user_id)
+        .execute(connection)?;
+    diesel::sql_query(
+        "DELETE FROM posts p
+         WHERE p.author = $1
+           AND NOT EXISTS (SELECT 1 FROM comments c WHERE c.post_id = p.id)")
+        .bind::<Integer, _>(user_id)
+        .execute(connection)?;
+
+    // A post needs a link or a body, so what's left is an empty text post.
+    diesel::update(posts::table.filter(posts::author.eq(user_id)))
+        .set((posts::title.eq(GHOST_USERNAME),
+              posts::link.eq(None::<String>),
+              posts::body.eq(""),
+              posts::body_html.eq(""),
+              posts::deleted_at.eq(now),
+              posts::author.eq(ghost)))
+        .execute(connection)?;
+    Ok(())
+}
@@ -0,0 +1,581 @@
+// The JSON API, for bots and our own tools. Everything lives under /api/v1 so
+// the urls and the shape of the responses can stay put while the HTML pages
+// change. Anything that would break a client goes in a /api/v2 instead.
+//
+// The handlers use the same queries as the HTML pages, but they never send
+// our models out as they are. A User has the password hash and email address
+// in it, so what goes out is one of the *Json structs below, which only have
+// the fields we are happy for anyone to see.
+//
+// Scripts log in with a personal API token from the account page, sent as
+// Authorization: Bearer <token>. What they can do depends on the token's
+// scopes, see auth.rs. From a browser the session cookie works too, with the
+// CSRF token in an X-CSRF-Token header on anything that isn't a GET. The
+// token comes from GET /api/v1/session.
+//
+// Lists are paged with the same ?after= and ?before= cursors as the pages,
+// and the next and prev fields of a response are the cursors to use.
+//
+// The OpenAPI description at /api/openapi.json is put together by utoipa from
+// the #[utoipa::path] attribute on each handler and the *Json types, so a new
+// endpoint needs both an entry in routes() and an attribute listed in ApiDoc.
+// A test in main.rs fails when the two don't agree.
+use std::fmt;
+use actix_web::error::BlockingError;
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError, Route};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+use super::auth::{Auth, Scope};
+use super::csrf::CsrfToken;
+use super::errors::AppError;
+use super::listing::{Listing, TopRange};
+use super::models::{Comment, CommentNode, NewPost, Post, User};
+use super::pagination::{Cursor, Page};
+use super::validation::{Errors, Validate};
+use super::verification;
+use super::{schema, find_profile, load_comment_page, profile_moved, save_comment, CommentForm,
+            CommentOutcome, Config, Pool, PostForm, ProfileLookup};
+
+pub fn configure_api(cfg: &mut web::ServiceConfig) {
+    cfg.route("/api/openapi.json", web::get().to(openapi));
+
+    let mut scope = web::scope(PREFIX)
+        // Bodies and urls we can't make sense of get a JSON error too.
+        .app_data(web::JsonConfig::default()
+            .limit(64 * 1024)
+            .error_handler(|e, _| ApiError::BadRequest(e.to_string()).into()))
+        .app_data(web::PathConfig::default()
+            .error_handler(|_, _| ApiError::App(AppError::NotFound).into()))
+        .app_data(web::QueryConfig::default()
+            .error_handler(|e, _| ApiError::BadRequest(e.to_string()).into()));
+    for (method, path, route) in routes() {
+        scope = scope.route(path, route.method(method));
+    }
+    cfg.service(scope.default_service(web::route().to(not_found)));
+}
+
+pub const PREFIX: &str = "/api/v1";
+
+// Every route under PREFIX. They're kept in a list rather than registered one
+// by one so the test in main.rs can hold them up against ApiDoc, actix has no
+// way of listing the routes of an App.
+pub fn routes() -> Vec<(Method, &'static str, Route)> {
+    vec![
+        (Method::GET, "/session", web::to(session)),
+        (Method::GET, "/posts", web::to(list_posts)),
+        (Method::POST, "/posts", web::to(create_post)),
+        (Method::GET, "/posts/{post_id}", web::to(get_post)),
+        (Method::POST, "/posts/{post_id}/comments", web::to(create_comment)),
+        (Method::GET, "/users/{username}", web::to(get_user)),
+    ]
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "The Oasis API", version = "1"),
+    paths(session, list_posts, get_post, create_post, create_comment, get_user),
+    components(schemas(
+        UserJson, PostJson, CommentJson, ProfileJson, SessionJson, PostPageJson,
+        PostListJson, CommentPageJson, super::PostForm, CommentRequest, ErrorJson, InvalidJson,
+    )),
+    modifiers(&SecuritySchemes),
+    tags(
+        (name = "posts", description = "Posts and their comments"),
+        (name = "users", description = "Profiles and who is logged in"),
+    ),
+)]
+pub struct ApiDoc;
+
+// The two ways in: a personal API token, or the session cookie from logging
+// in on the site.
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "token",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()));
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("auth-cookie"))));
+    }
+}
+
+async fn openapi() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+// Errors come back as {"error": "..."}, or for a request that didn't pass
+// validation as {"errors": {"field": "..."}} with a 422, the same messages
+// the forms show.
+#[derive(Debug)]
+pub enum ApiError {
+    App(AppError),
+    BadRequest(String),
+    Unauthorized,
+    Invalid(Errors),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::App(e) => write!(f, "{}", e),
+            ApiError::BadRequest(message) => write!(f, "{}", message),
+            ApiError::Unauthorized => write!(f, "Not logged in."),
+            ApiError::Invalid(_) => write!(f, "Invalid request."),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::App(e) => e.status_code(),
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+
+        let error = match self {
+            ApiError::Invalid(errors) => return HttpResponse::build(status).json(InvalidJson { errors }),
+            // The details of our own failures stay in the log.
+            ApiError::App(e) if status.is_server_error() => {
+                log::error!("{}", e);
+                "Something went wrong on our end.".to_string()
+            }
+            e => e.to_string(),
+        };
+
+        HttpResponse::build(status).json(ErrorJson { error })
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorJson {
+    #[schema(example = "Not logged in.")]
+    pub error: String,
+}
+
+// The message for each field that wasn't right, keyed by the field's name.
+#[derive(Serialize, ToSchema)]
+pub struct InvalidJson<'a> {
+    #[schema(value_type = BTreeMap<String, String>)]
+    pub errors: &'a Errors,
+}
+
+impl From<AppError> for ApiError {
+    fn from(e: AppError) -> Self {
+        ApiError::App(e)
+    }
+}
+
+impl From<BlockingError<AppError>> for ApiError {
+    fn from(e: BlockingError<AppError>) -> Self {
+        ApiError::App(e.into())
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UserJson {
+    pub username: String,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl From<&User> for UserJson {
+    fn from(user: &User) -> Self {
+        UserJson {
+            username: user.username.clone(),
+            created_at: user.created_at,
+        }
+    }
+}
+
+// A deleted post keeps its id, but everything the author wrote is left out.
+#[derive(Serialize, ToSchema)]
+pub struct PostJson {
+    pub id: i32,
+    pub title: Option<String>,
+    pub link: Option<String>,
+    pub body: Option<String>,
+    pub body_html: Option<String>,
+    pub author: Option<UserJson>,
+    pub score: i32,
+    pub created_at: chrono::NaiveDateTime,
+    pub edited_at: Option<chrono::NaiveDateTime>,
+    pub deleted: bool,
+}
+
+impl PostJson {
+    pub fn new(post: &Post, author: &User) -> Self {
+        let deleted = post.deleted_at.is_some();
+        let visible = |value: &Option<String>| if deleted { None } else { value.clone() };
+
+        PostJson {
+            id: post.id,
+            title: if deleted { None } else { Some(post.title.clone()) },
+            link: visible(&post.link),
+            body: visible(&post.body),
+            body_html: visible(&post.body_html),
+            author: if deleted { None } else { Some(author.into()) },
+            score: post.score,
+            created_at: post.created_at,
+            edited_at: post.edited_at,
+            deleted,
+        }
+    }
+}
+
+// Comments come as a tree, the same way the post page shows them.
+#[derive(Serialize, ToSchema)]
+pub struct CommentJson {
+    pub id: i32,
+    pub post_id: i32,
+    pub parent_id: Option<i32>,
+    pub comment: Option<String>,
+    pub comment_html: Option<String>,
+    pub author: Option<UserJson>,
+    pub created_at: chrono::NaiveDateTime,
+    pub edited_at: Option<chrono::NaiveDateTime>,
+    pub deleted: bool,
+    pub replies: Vec<CommentJson>,
+}
+
+impl CommentJson {
+    pub fn new(comment: &Comment, author: &User, replies: Vec<CommentJson>) -> Self {
+        let deleted = comment.deleted_at.is_some();
+
+        CommentJson {
+            id: comment.id,
+            post_id: comment.post_id,
+            parent_id: comment.parent_comment_id,
+            comment: if deleted { None } else { Some(comment.comment.clone()) },
+            comment_html: if deleted { None } else { comment.comment_html.clone() },
+            author: if deleted { None } else { Some(author.into()) },
+            created_at: comment.created_at,
+            edited_at: comment.edited_at,
+            deleted,
+            replies,
+        }
+    }
+
+    fn from_node(node: &CommentNode) -> Self {
+        let replies = node.replies.iter().map(CommentJson::from_node).collect();
+        CommentJson::new(&node.comment, &node.user, replies)
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ProfileJson {
+    #[serde(flatten)]
+    pub user: UserJson,
+    pub post_count: i64,
+    pub comment_count: i64,
+}
+
+// A page of things, with the cursors for the pages either side.
+#[derive(Serialize, ToSchema)]
+#[aliases(PostListJson = PageJson<PostJson>, CommentPageJson = PageJson<CommentJson>)]
+pub struct PageJson<T> {
+    items: Vec<T>,
+    next: Option<i32>,
+    prev: Option<i32>,
+}
+
+impl<T> PageJson<T> {
+    fn new<U>(page: Page<U>, item: impl Fn(&U) -> T) -> Self {
+        PageJson {
+            items: page.items.iter().map(item).collect(),
+            next: page.next,
+            prev: page.prev,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SessionJson {
+    pub user: Option<UserJson>,
+    pub csrf_token: String,
+}
+
+// A post along with the first page of its comment threads.
+#[derive(Serialize, ToSchema)]
+pub struct PostPageJson {
+    pub post: PostJson,
+    pub comments: CommentPageJson,
+}
+
+// Who is logged in, if anyone, and the CSRF token to send back with anything
+// that changes something. A token needs the read scope to be told whose it is.
+#[utoipa::path(
+    get,
+    path = "/api/v1/session",
+    tag = "users",
+    responses(
+        (status = 200, description = "Who is logged in, if anyone", body = SessionJson),
+        (status = 403, description = "The token doesn't have the read scope", body = ErrorJson),
+    ),
+    security((), ("token" = []), ("session" = [])),
+)]
+async fn session(auth: Auth,
+                 pool: web::Data<Pool>,
+                 csrf: CsrfToken) -> Result<HttpResponse, ApiError> {
+    let user = web::block(move || -> Result<Option<User>, AppError> {
+        let connection = pool.get()?;
+        auth.user(&connection, Scope::Read)
+    }).await?;
+
+    Ok(HttpResponse::Ok().json(SessionJson {
+        user: user.as_ref().map(UserJson::from),
+        csrf_token: csrf.0,
+    }))
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    sort: Option<String>,
+    t: Option<String>,
+}
+
+// GET /api/v1/posts?sort=hot|new|top, with t=day|week|month|all for top.
+#[utoipa::path(
+    get,
+    path = "/api/v1/posts",
+    tag = "posts",
+    params(
+        ("sort" = Option<String>, Query, description = "hot (the default), new or top"),
+        ("t" = Option<String>, Query, description = "For top: day, week, month or all"),
+        ("after" = Option<i32>, Query, description = "The next cursor from the previous page"),
+        ("before" = Option<i32>, Query, description = "The prev cursor from the previous page"),
+    ),
+    responses(
+        (status = 200, description = "A page of posts", body = PostListJson),
+        (status = 400, description = "Unknown sort or cursor", body = ErrorJson),
+    ),
+)]
+async fn list_posts(pool: web::Data<Pool>,
+                    config: web::Data<Config>,
+                    web::Query(query): web::Query<ListQuery>,
+                    web::Query(cursor): web::Query<Cursor>) -> Result<HttpResponse, ApiError> {
+    let listing = match query.sort.as_deref() {
+        None | Some("hot") => Listing::Hot,
+        Some("new") => Listing::New,
+        Some("top") => Listing::Top(TopRange::from_query(query.t.as_deref())),
+        Some(other) => return Err(ApiError::BadRequest(format!("Unknown sort {:?}.", other))),
+    };
+    let page_size = config.page_size;
+
+    let page = web::block(move || -> Result<Page<(Post, User)>, AppError> {
+        let connection = pool.get()?;
+        Ok(listing.load(&connection, cursor.direction(), page_size)?)
+    }).await?;
+
+    Ok(HttpResponse::Ok().json(PageJson::new(page, |(p, u)| PostJson::new(p, u))))
+}
+
+// GET /api/v1/posts/{post_id}, the post and a page of its comment threads.
+#[utoipa::path(
+    get,
+    path = "/api/v1/posts/{post_id}",
+    tag = "posts",
+    params(
+        ("post_id" = i32, Path, description = "The post's id"),
+        ("after" = Option<i32>, Query, description = "The next cursor from the previous page of comments"),
+        ("before" = Option<i32>, Query, description = "The prev cursor from the previous page of comments"),
+    ),
+    responses(
+        (status = 200, description = "The post and a page of its comment threads", body = PostPageJson),
+        (status = 404, description = "There's no such post", body = ErrorJson),
+    ),
+)]
+async fn get_post(pool: web::Data<Pool>,
+                  config: web::Data<Config>,
+                  web::Path(post_id): web::Path<i32>,
+                  web::Query(cursor): web::Query<Cursor>) -> Result<HttpResponse, ApiError> {
+    use schema::posts::dsl::{posts};
+    use schema::users::dsl::{users};
+
+    let page_size = config.page_size;
+
+    let (post, author, comments) = web::block(move || -> Result<_, AppError> {
+        let connection = pool.get()?;
+        let post: Post = posts.find(post_id).first(&connection)?;
+        let author: User = users.find(post.author).first(&connection)?;
+        let comments = load_comment_page(&connection, &post, cursor.direction(), page_size)?;
+        Ok((post, author, comments))
+    }).await?;
+
+    Ok(HttpResponse::Ok().json(PostPageJson {
+        post: PostJson::new(&post, &author),
+        comments: PageJson::new(comments, CommentJson::from_node),
+    }))
+}
+
+// POST /api/v1/posts with {"title": ..., "link": ..., "body": ...}. Link and
+// body are each optional but one of them has to be there, the same as the
+// submission form.
+#[utoipa::path(
+    post,
+    path = "/api/v1/posts",
+    tag = "posts",
+    request_body = super::PostForm,
+    responses(
+        (status = 201, description = "The new post", body = PostJson),
+        (status = 400, description = "The body isn't JSON of the right shape", body = ErrorJson),
+        (status = 401, description = "Not logged in", body = ErrorJson),
+        (status = 403, description = "The token doesn't have the post scope, or the email address isn't verified", body = ErrorJson),
+        (status = 422, description = "A field isn't valid", body = InvalidJson),
+    ),
+    security(("token" = []), ("session" = [])),
+)]
+async fn create_post(auth: Auth,
+                     pool: web::Data<Pool>,
+                     config: web::Data<Config>,
+                     web::Json(form): web::Json<PostForm>) -> Result<HttpResponse, ApiError> {
+    use schema::posts;
+
+    if auth.is_anonymous() {
+        return Err(ApiError::Unauthorized);
+    }
+    form.validate().map_err(ApiError::Invalid)?;
+
+    let created = web::block(move || -> Result<Option<(Post, User)>, AppError> {
+        let connection = pool.get()?;
+        let user = match auth.user(&connection, Scope::Post)? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        verification::check(&user, Scope::Post, &config)?;
+
+        let new_post = NewPost::from_post_form(form.title, form.link, form.body, user.id);
+        let post = diesel::insert_into(posts::table)
+            .values(&new_post)
+            .get_result::<Post>(&connection)?;
+        Ok(Some((post, user)))
+    }).await?;
+
+    let (post, author) = created.ok_or(ApiError::Unauthorized)?;
+    Ok(HttpResponse::Created()
+        .header(header::LOCATION, format!("/api/v1/posts/{}", post.id))
+        .json(PostJson::new(&post, &author)))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CommentRequest {
+    comment: String,
+    // The comment being replied to, if this is a reply.
+    parent_id: Option<i32>,
+}
+
+// POST /api/v1/posts/{post_id}/comments with {"comment": ..., "parent_id": ...}.
+#[utoipa::path(
+    post,
+    path = "/api/v1/posts/{post_id}/comments",
+    tag = "posts",
+    params(("post_id" = i32, Path, description = "The post being commented on")),
+    request_body = CommentRequest,
+    responses(
+        (status = 201, description = "The new comment", body = CommentJson),
+        (status = 400, description = "The body isn't JSON of the right shape, or the parent isn't on this post", body = ErrorJson),
+        (status = 401, description = "Not logged in", body = ErrorJson),
+        (status = 403, description = "The token doesn't have the comment scope, or the email address isn't verified", body = ErrorJson),
+        (status = 404, description = "There's no such post", body = ErrorJson),
+        (status = 422, description = "The comment isn't valid", body = InvalidJson),
+    ),
+    security(("token" = []), ("session" = [])),
+)]
+async fn create_comment(auth: Auth,
+                        pool: web::Data<Pool>,
+                        config: web::Data<Config>,
+                        web::Path(post_id): web::Path<i32>,
+                        web::Json(request): web::Json<CommentRequest>) -> Result<HttpResponse, ApiError> {
+    use schema::posts::dsl::{posts};
+    use schema::comments;
+
+    if auth.is_anonymous() {
+        return Err(ApiError::Unauthorized);
+    }
+    let form = CommentForm { comment: request.comment };
+    form.validate().map_err(ApiError::Invalid)?;
+    let parent_id = request.parent_id;
+
+    let outcome = web::block(move || -> Result<CommentOutcome, AppError> {
+        let connection = pool.get()?;
+        let post: Post = posts.find(post_id).first(&connection)?;
+
+        // Replies have to be to a comment on the same post.
+        if let Some(parent_id) = parent_id {
+            let parent: Option<Comment> = Comment::belonging_to(&post)
+                .filter(comments::id.eq(parent_id))
+                .first(&connection)
+                .optional()?;
+            if parent.is_none() {
+                return Ok(CommentOutcome::UnknownParent);
+            }
+        }
+
+        save_comment(&connection, &config, &auth, &post, form.comment, parent_id)
+    }).await?;
+
+    match outcome {
+        CommentOutcome::Saved(comment, author) => Ok(HttpResponse::Created()
+            .json(CommentJson::new(&comment, &author, Vec::new()))),
+        CommentOutcome::UnknownParent => Err(ApiError::BadRequest("Comment not found on this post.".to_string())),
+        CommentOutcome::UnknownUser => Err(ApiError::Unauthorized),
+    }
+}
+
+// GET /api/v1/users/{username}
+#[utoipa::path(
+    get,
+    path = "/api/v1/users/{username}",
+    tag = "users",
+    params(("username" = String, Path, description = "Whose profile")),
+    responses(
+        (status = 200, description = "The user and how much they've posted", body = ProfileJson),
+        (status = 301, description = "The user has changed their name, Location has the new url"),
+        (status = 404, description = "There's no such user", body = ErrorJson),
+    ),
+)]
+async fn get_user(req: HttpRequest,
+                  pool: web::Data<Pool>,
+                  web::Path(profile_name): web::Path<String>) -> Result<HttpResponse, ApiError> {
+    use schema::posts::dsl::{posts, author, deleted_at as post_deleted_at};
+    use schema::comments::dsl::{comments, user_id, deleted_at as comment_deleted_at};
+
+    let profile = web::block(move || -> Result<ProfileLookup<ProfileJson>, AppError> {
+        let connection = pool.get()?;
+        // Old names lead to the new one, the same as the profile pages.
+        let user = match find_profile(&connection, &profile_name)? {
+            ProfileLookup::Found(user) => user,
+            ProfileLookup::Renamed(current) => return Ok(ProfileLookup::Renamed(current)),
+        };
+
+        let post_count: i64 = posts.filter(author.eq(user.id))
+            .filter(post_deleted_at.is_null())
+            .count()
+            .get_result(&connection)?;
+        let comment_count: i64 = comments.filter(user_id.eq(user.id))
+            .filter(comment_deleted_at.is_null())
+            .count()
+            .get_result(&connection)?;
+
+        Ok(ProfileLookup::Found(ProfileJson { user: (&user).into(), post_count, comment_count }))
+    }).await?;
+
+    match profile {
+        ProfileLookup::Found(profile) => Ok(HttpResponse::Ok().json(profile)),
+        ProfileLookup::Renamed(current) => Ok(profile_moved(&req, &format!("{}/users/{}", PREFIX, current))),
+    }
+}
+
+async fn not_found(_req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    Err(ApiError::App(AppError::NotFound))
+}
@@ -0,0 +1,131 @@
+// Working out who is behind a request. People in a browser are logged in
+// through their session cookie (see identity.rs), scripts and bots send a
+// personal API token in an Authorization: Bearer header instead. Handlers that
+// accept both take an Auth and ask it for the user inside web::block, the
+// same way the others look up the Identity's session.
+//
+// A token only allows what its scopes say. A session can do anything.
+//
+// Requests with a bearer token don't carry the session cookie's authority,
+// so the CSRF middleware lets them through without a CSRF token. That's why
+// SessionPolicy ignores the cookie on any request with an Authorization
+// header, even if the browser sent it along too.
+use actix_identity::RequestIdentity;
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::{Error, FromRequest, HttpRequest};
+use diesel::prelude::*;
+use futures_util::future::{ok, Ready};
+use super::errors::AppError;
+use super::models::{hash_token, ApiToken, User};
+use super::schema;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    Read,
+    Post,
+    Comment,
+    Vote,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 4] = [Scope::Read, Scope::Post, Scope::Comment, Scope::Vote];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Post => "post",
+            Scope::Comment => "comment",
+            Scope::Vote => "vote",
+        }
+    }
+
+    // What a token with this scope is allowed to do, in the words of the
+    // error when it isn't.
+    pub fn action(&self) -> &'static str {
+        match self {
+            Scope::Read => "read your account",
+            Scope::Post => "submit posts",
+            Scope::Comment => "comment",
+            Scope::Vote => "vote",
+        }
+    }
+}
+
+pub enum Auth {
+    Session(String),
+    Token(String),
+    Anonymous,
+}
+
+impl Auth {
+    pub fn is_anonymous(&self) -> bool {
+        matches!(self, Auth::Anonymous)
+    }
+
+    // The session token, for the pages that show the logged in user's view of
+    // things after a form is sent.
+    pub fn session(&self) -> Option<String> {
+        match self {
+            Auth::Session(token) => Some(token.clone()),
+            _ => None,
+        }
+    }
+
+    // The user behind the request, if there is one. A token that doesn't have
+    // the scope the handler needs is refused with a 403 rather than treated
+    // as nobody, so the script's author can tell what went wrong.
+    pub fn user(&self, connection: &PgConnection, scope: Scope) -> Result<Option<User>, AppError> {
+        use schema::api_tokens::dsl::{api_tokens, token_hash, last_used_at};
+        use schema::users;
+
+        match self {
+            Auth::Session(token) => Ok(super::current_user(connection, Some(token))?),
+            Auth::Token(token) => {
+                let found: Option<(ApiToken, User)> = api_tokens.inner_join(users::table)
+                    .filter(token_hash.eq(hash_token(token)))
+                    .first(connection)
+                    .optional()?;
+
+                match found {
+                    Some((api_token, _)) if !api_token.scopes.iter().any(|s| s == scope.name()) => {
+                        Err(AppError::Forbidden(format!("This token isn't allowed to {}.", scope.action())))
+                    }
+                    Some((api_token, user)) => {
+                        diesel::update(api_tokens.find(api_token.id))
+                            .set(last_used_at.eq(chrono::Utc::now().naive_utc()))
+                            .execute(connection)?;
+                        Ok(Some(user))
+                    }
+                    None => Ok(None),
+                }
+            }
+            Auth::Anonymous => Ok(None),
+        }
+    }
+}
+
+// The bearer token from the Authorization header, if the request has one.
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let mut parts = value.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim().to_string()),
+        _ => None,
+    }
+}
+
+impl FromRequest for Auth {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let auth = match (bearer_token(req), req.get_identity()) {
+            (Some(token), _) => Auth::Token(token),
+            (None, Some(token)) => Auth::Session(token),
+            (None, None) => Auth::Anonymous,
+        };
+        ok(auth)
+    }
+}
@@ -0,0 +1,299 @@
+// Cross site request forgery protection. Without it any other website could
+// put a form on its page that posts to us, and the browser would happily send
+// the user's login cookie along with it.
+//
+// Our forms carry a token in a hidden csrf_token field (the csrf_field macro
+// in macros.html) and the middleware turns away any POST whose field doesn't
+// match. Other sites can't read our pages or cookies, so they have no way of
+// filling the field in. Scripts can send the token in an X-CSRF-Token header
+// instead.
+//
+// Once someone is logged in the token belongs to their session: it's an HMAC
+// of the session's token, so it changes with every login and stops working
+// when the session ends. Before that, every browser gets a random token in the
+// signed csrf-token cookie, which lasts until the browser is closed. The
+// cookie is replaced whenever someone logs in or out, so a token that was on
+// a page before can't be used after.
+//
+// Both kinds are signed with the cookie key. While an old key is still in its
+// grace period after a rotation (see identity.rs) tokens made with it are let
+// through too, so nobody's open forms stop working, and the cookie is signed
+// again with the new key on the way out.
+//
+// This has to run inside IdentityService, which is what tells us about the
+// session.
+//
+// Requests with an Authorization header are let through without a token. They
+// are logged in with an API token rather than the cookie (see auth.rs), and
+// another site can't make a browser add that header to a request.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use actix_identity::RequestIdentity;
+use actix_service::{Service, Transform};
+use actix_web::cookie::{Cookie, CookieJar, Key};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use futures_util::future::{ok, FutureExt, LocalBoxFuture, Ready};
+use futures_util::stream::StreamExt;
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+use sha2::Sha256;
+use super::config::CookieConfig;
+use super::errors::AppError;
+
+const COOKIE_NAME: &str = "csrf-token";
+const FIELD_NAME: &str = "csrf_token";
+const HEADER_NAME: &str = "x-csrf-token";
+
+// The forms we check are all small, anything bigger than this isn't one of
+// ours.
+const MAX_FORM_SIZE: usize = 64 * 1024;
+
+pub struct Csrf {
+    settings: Rc<Settings>,
+}
+
+struct Settings {
+    current: SigningKey,
+    previous: Option<(SigningKey, chrono::NaiveDateTime)>,
+    secure: bool,
+    same_site: actix_web::cookie::SameSite,
+}
+
+// One cookie key, in the two forms we use it in.
+struct SigningKey {
+    cookie: Key,
+    // For making the tokens of logged in users.
+    secret: Vec<u8>,
+}
+
+impl SigningKey {
+    fn new(key: &[u8]) -> Self {
+        SigningKey { cookie: Key::derive_from(key), secret: key.to_vec() }
+    }
+}
+
+impl Settings {
+    // The keys tokens are accepted from right now, the current one first.
+    fn keys(&self, now: chrono::NaiveDateTime) -> impl Iterator<Item = &SigningKey> {
+        let previous = self.previous.iter()
+            .filter(move |(_, until)| now < *until)
+            .map(|(key, _)| key);
+        std::iter::once(&self.current).chain(previous)
+    }
+}
+
+impl Csrf {
+    pub fn new(config: &CookieConfig) -> Self {
+        let previous = match (&config.previous_key, config.previous_key_until) {
+            (Some(key), Some(until)) => Some((SigningKey::new(key), until)),
+            _ => None,
+        };
+        Csrf {
+            settings: Rc::new(Settings {
+                current: SigningKey::new(&config.key),
+                previous,
+                secure: config.secure,
+                same_site: config.same_site,
+            }),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for Csrf
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CsrfMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            settings: self.settings.clone(),
+        })
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    settings: Rc<Settings>,
+}
+
+impl<S, B> Service for CsrfMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let settings = self.settings.clone();
+
+        async move {
+            // A browser without a token (or with one we didn't sign) gets a
+            // new one. A form posted from it can't match, which is what we
+            // want, since we never showed it a form with that token in it.
+            let now = chrono::Utc::now().naive_utc();
+            let cookie = settings.keys(now)
+                .enumerate()
+                .find_map(|(i, key)| read_cookie(&req, &key.cookie).map(|token| (token, i > 0)));
+            let (browser_token, is_new, stale) = match cookie {
+                Some((token, stale)) => (token, false, stale),
+                None => (new_token(), true, false),
+            };
+
+            // What the forms on our pages get, and what a form may carry.
+            let session = req.get_identity();
+            let (token, accepted): (String, Vec<String>) = match &session {
+                Some(session) => (session_token(&settings.current.secret, session),
+                                  settings.keys(now).map(|key| session_token(&key.secret, session)).collect()),
+                None => (browser_token.clone(), vec![browser_token.clone()]),
+            };
+
+            if !is_safe(req.method()) && !req.headers().contains_key(header::AUTHORIZATION) {
+                let submitted = match submitted_token(&mut req).await {
+                    Ok(submitted) => submitted,
+                    Err(e) => return Ok(req.error_response(e)),
+                };
+                let valid = submitted.map_or(false, |s| accepted.iter().any(|a| constant_time_eq(&s, a)))
+                    && (session.is_some() || !is_new);
+                if !valid {
+                    return Ok(req.error_response(AppError::Forbidden(
+                        "This form has expired or didn't come from us. Go back, reload the page and try again.".to_string()
+                    )));
+                }
+            }
+
+            req.extensions_mut().insert(CsrfToken(token));
+
+            let fut = service.borrow_mut().call(req);
+            let mut res = fut.await?;
+
+            // Logged in or out, the browser starts over with a token nobody
+            // has seen yet.
+            let changed = res.request().get_identity() != session;
+            if is_new || stale || changed {
+                let browser_token = if changed { new_token() } else { browser_token };
+                let mut jar = CookieJar::new();
+                jar.signed(&settings.current.cookie).add(Cookie::build(COOKIE_NAME, browser_token)
+                    .path("/")
+                    .http_only(true)
+                    .secure(settings.secure)
+                    .same_site(settings.same_site)
+                    .finish());
+                for cookie in jar.delta() {
+                    res.response_mut().add_cookie(cookie)?;
+                }
+            }
+
+            Ok(res)
+        }.boxed_local()
+    }
+}
+
+// GET and friends only ever read, so they don't need a token.
+fn is_safe(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE)
+}
+
+fn read_cookie(req: &ServiceRequest, key: &Key) -> Option<String> {
+    let cookie = req.cookie(COOKIE_NAME)?;
+    let mut jar = CookieJar::new();
+    jar.add_original(cookie);
+    jar.signed(key).get(COOKIE_NAME).map(|c| c.value().to_string())
+}
+
+// The token from the X-CSRF-Token header, or failing that from the csrf_token
+// field of a url encoded form. Reading the form uses up the request body, so
+// we put the bytes back afterwards for the handler's web::Form to read.
+async fn submitted_token(req: &mut ServiceRequest) -> Result<Option<String>, Error> {
+    if let Some(token) = req.headers().get(HEADER_NAME).and_then(|h| h.to_str().ok()) {
+        return Ok(Some(token.to_string()));
+    }
+
+    let is_form = req.headers().get(header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .map_or(false, |t| t.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Ok(None);
+    }
+
+    let mut payload = req.take_payload();
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > MAX_FORM_SIZE {
+            return Err(actix_web::error::ErrorPayloadTooLarge("Form is too large."));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let body = body.freeze();
+
+    let fields: HashMap<String, String> = serde_urlencoded::from_bytes(&body).unwrap_or_default();
+
+    let replay: actix_web::dev::PayloadStream =
+        Box::pin(futures_util::stream::once(futures_util::future::ready(Ok(body))));
+    req.set_payload(Payload::Stream(replay));
+
+    Ok(fields.get(FIELD_NAME).cloned())
+}
+
+// The token for a logged in session. The label keeps it from being the same
+// as any other HMAC we make with the cookie key.
+fn session_token(secret: &[u8], session: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(format!("csrf:{}", session).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn new_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(43)
+        .collect()
+}
+
+// Compares every byte no matter where the first difference is, so how long the
+// check takes doesn't give away how much of a guess was right.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// The token for the current session or browser, for handlers to hand to their templates
+// as csrf_token. Outside of the middleware (in tests, say) this is empty, and
+// a form carrying an empty token will never be accepted.
+#[derive(Clone)]
+pub struct CsrfToken(pub String);
+
+impl FromRequest for CsrfToken {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = req.extensions().get::<CsrfToken>()
+            .cloned()
+            .unwrap_or_else(|| CsrfToken(String::new()));
+        ok(token)
+    }
+}
@@ -0,0 +1,141 @@
+// Handlers return Result<HttpResponse, AppError> so that anything that goes
+// wrong becomes a proper HTTP response instead of a panic. The ? operator
+// turns diesel, pool, tera, argonautica, mail and web::block errors into an
+// AppError for us through the From impls below.
+// Missing rows become a 404 and UNIQUE constraint violations become a 409
+// Conflict. Requests we won't carry out, like a form without its CSRF token,
+// are a 403, and ones that have to wait a while first are a 429. Anything
+// else is our fault, so it gets logged and the user sees a 500. The 404 and
+// 500 pages themselves are rendered by the error handlers registered in
+// main().
+use std::fmt;
+use actix_web::{HttpResponse, ResponseError};
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use super::mailer::MailError;
+
+#[derive(Debug)]
+pub enum AppError {
+    NotFound,
+    // The message is meant to be shown to the user next to the form they
+    // submitted.
+    Conflict(String),
+    // Also shown to the user, for requests we refuse to carry out.
+    Forbidden(String),
+    // Come back in this many seconds, see throttle.rs.
+    TooManyRequests(i64),
+    Database(DieselError),
+    Pool(r2d2::Error),
+    Template(tera::Error),
+    Hashing(argonautica::Error),
+    Mail(MailError),
+    // The blocking thread pool went away before our database work finished,
+    // which only happens while the server is shutting down.
+    Canceled,
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::NotFound => write!(f, "Not found."),
+            AppError::Conflict(message) => write!(f, "{}", message),
+            AppError::Forbidden(message) => write!(f, "{}", message),
+            AppError::TooManyRequests(seconds) => write!(
+                f, "Too many failed attempts. Try again in {}.", super::duration_in_words(*seconds)),
+            AppError::Database(e) => write!(f, "Database error: {}", e),
+            AppError::Pool(e) => write!(f, "Connection pool error: {}", e),
+            AppError::Template(e) => write!(f, "Template error: {:?}", e),
+            AppError::Hashing(e) => write!(f, "Password hashing error: {:?}", e),
+            AppError::Mail(e) => write!(f, "Mail error: {}", e),
+            AppError::Canceled => write!(f, "Blocking task was canceled."),
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+
+        if status.is_server_error() {
+            log::error!("{}", self);
+        }
+
+        match self {
+            AppError::Conflict(message) | AppError::Forbidden(message) =>
+                HttpResponse::build(status).body(message.clone()),
+            AppError::TooManyRequests(seconds) => HttpResponse::build(status)
+                .header(actix_web::http::header::RETRY_AFTER, seconds.to_string())
+                .body(self.to_string()),
+            _ => HttpResponse::build(status).finish(),
+        }
+    }
+}
+
+impl From<DieselError> for AppError {
+    fn from(e: DieselError) -> Self {
+        match e {
+            DieselError::NotFound => AppError::NotFound,
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+                AppError::Conflict(conflict_message(info.constraint_name()))
+            }
+            e => AppError::Database(e),
+        }
+    }
+}
+
+impl From<r2d2::Error> for AppError {
+    fn from(e: r2d2::Error) -> Self {
+        AppError::Pool(e)
+    }
+}
+
+impl From<tera::Error> for AppError {
+    fn from(e: tera::Error) -> Self {
+        AppError::Template(e)
+    }
+}
+
+impl From<argonautica::Error> for AppError {
+    fn from(e: argonautica::Error) -> Self {
+        AppError::Hashing(e)
+    }
+}
+
+impl From<MailError> for AppError {
+    fn from(e: MailError) -> Self {
+        AppError::Mail(e)
+    }
+}
+
+// Database work runs inside web::block, which hands back our own error wrapped
+// up with the case where the thread pool was torn down underneath it.
+impl From<BlockingError<AppError>> for AppError {
+    fn from(e: BlockingError<AppError>) -> Self {
+        match e {
+            BlockingError::Error(e) => e,
+            BlockingError::Canceled => AppError::Canceled,
+        }
+    }
+}
+
+// Postgres tells us which constraint was violated, which is enough to tell the
+// user what to change. The names are the ones postgres generated for the
+// UNIQUE constraints in our migrations.
+fn conflict_message(constraint: Option<&str>) -> String {
+    match constraint {
+        Some("users_username_key") => "That username is already taken.",
+        Some("users_email_key") => "An account with that email already exists.",
+        _ => "That already exists.",
+    }.to_string()
+}
@@ -0,0 +1,149 @@
+// RSS and Atom feeds, so people can follow the listings, a user's submissions
+// or the discussion on a post from a feed reader. The handlers load the same
+// rows the HTML pages show and turn them into Entries here, and the rss.xml
+// and atom.xml templates do the rest. Tera escapes everything in .xml
+// templates just like in .html ones, which is what XML needs too.
+//
+// Feed readers live somewhere else, so every link in a feed is a full url
+// starting with the SITE_URL setting.
+use actix_web::HttpResponse;
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tera::{Context, Tera};
+use super::errors::AppError;
+use super::models::{Comment, Post, User};
+
+// Which kind of feed was asked for, from the end of the url.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Rss,
+    Atom,
+}
+
+impl Format {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Rss => "rss",
+            Format::Atom => "atom",
+        }
+    }
+
+    fn template(&self) -> &'static str {
+        match self {
+            Format::Rss => "rss.xml",
+            Format::Atom => "atom.xml",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Format::Rss => "application/rss+xml; charset=utf-8",
+            Format::Atom => "application/atom+xml; charset=utf-8",
+        }
+    }
+}
+
+// A timestamp in both of the formats feeds use: RFC 822 for RSS and RFC 3339
+// for Atom. Our timestamps are all UTC.
+#[derive(Debug, Serialize)]
+pub struct Timestamp {
+    pub rfc822: String,
+    pub rfc3339: String,
+}
+
+impl Timestamp {
+    pub fn new(time: NaiveDateTime) -> Self {
+        let time = DateTime::<Utc>::from_utc(time, Utc);
+        Timestamp {
+            rfc822: time.to_rfc2822(),
+            rfc3339: time.to_rfc3339_opts(SecondsFormat::Secs, true),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    // Feed readers use the id to tell which entries they've already seen, so
+    // it has to stay the same for as long as the post or comment exists.
+    pub id: String,
+    pub title: String,
+    // Where the entry points: a link post's link, otherwise its page.
+    pub link: String,
+    // The discussion page, for the RSS comments element.
+    pub comments: String,
+    pub author: String,
+    pub published: Timestamp,
+    pub updated: Timestamp,
+    #[serde(skip)]
+    pub updated_at: NaiveDateTime,
+    // The rendered markdown, if there is any. It is escaped in the feed, which
+    // is how both formats expect HTML content to be sent.
+    pub content_html: Option<String>,
+}
+
+impl Entry {
+    pub fn from_post(site_url: &str, post: &Post, author: &User) -> Self {
+        let page = format!("{}/post/{}", site_url, post.id);
+        Entry {
+            id: page.clone(),
+            title: post.title.clone(),
+            link: post.link.clone().unwrap_or_else(|| page.clone()),
+            comments: page,
+            author: author.username.clone(),
+            published: Timestamp::new(post.created_at),
+            updated: Timestamp::new(post.edited_at.unwrap_or(post.created_at)),
+            updated_at: post.edited_at.unwrap_or(post.created_at),
+            content_html: post.body_html.clone(),
+        }
+    }
+
+    pub fn from_comment(site_url: &str, comment: &Comment, post: &Post, author: &User) -> Self {
+        let page = format!("{}/post/{}", site_url, post.id);
+        let link = format!("{}#comment-{}", page, comment.id);
+        let post_title = if post.deleted_at.is_some() { "[deleted]" } else { post.title.as_str() };
+        Entry {
+            id: link.clone(),
+            title: format!("{} on {}", author.username, post_title),
+            link,
+            comments: page,
+            author: author.username.clone(),
+            published: Timestamp::new(comment.created_at),
+            updated: Timestamp::new(comment.edited_at.unwrap_or(comment.created_at)),
+            updated_at: comment.edited_at.unwrap_or(comment.created_at),
+            content_html: comment.comment_html.clone(),
+        }
+    }
+}
+
+pub struct Feed {
+    pub title: String,
+    // The HTML page this is the feed of.
+    pub link: String,
+    // The feed's own url, which Atom wants to be told.
+    pub self_link: String,
+    pub entries: Vec<Entry>,
+}
+
+impl Feed {
+    pub fn render(&self, tera: &Tera, format: Format) -> Result<HttpResponse, AppError> {
+        // The feed was last updated when its newest entry was, or right now
+        // when there aren't any entries yet.
+        let updated = self.entries.iter()
+            .map(|e| e.updated_at)
+            .max()
+            .unwrap_or_else(|| Utc::now().naive_utc());
+
+        let mut data = Context::new();
+        data.insert("title", &self.title);
+        data.insert("link", &self.link);
+        data.insert("self_link", &self.self_link);
+        data.insert("updated", &Timestamp::new(updated));
+        data.insert("entries", &self.entries);
+
+        let rendered = tera.render(format.template(), &data)?;
+        Ok(HttpResponse::Ok()
+            .content_type(format.content_type())
+            .body(rendered))
+    }
+}
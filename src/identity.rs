@@ -0,0 +1,183 @@
+// The login cookie is signed and encrypted with the key from the config, so a
+// user can't just write their own auth-cookie for someone else's username.
+// CookieIdentityPolicy only knows about one key, so when the key is rotated we
+// keep a second policy around with the previous key. Cookies it can read are
+// still accepted until the grace period runs out, and get written again with
+// the new key on the way out, so nobody is logged out by a rotation.
+//
+// What the cookie holds is the token of a row in the sessions table, not the
+// username. SessionPolicy sits on top of the cookie and only lets the token
+// through while its session still exists, so deleting the row logs that
+// browser out on its next request.
+use actix_identity::{CookieIdentityPolicy, IdentityPolicy};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::{web, Error, HttpMessage};
+use diesel::prelude::*;
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use super::config::CookieConfig;
+use super::errors::AppError;
+use super::models::Session;
+use super::schema;
+use super::Pool;
+
+pub struct RotatingCookiePolicy {
+    current: CookieIdentityPolicy,
+    previous: Option<(CookieIdentityPolicy, chrono::NaiveDateTime)>,
+}
+
+// Left on the request when its cookie was signed with the previous key.
+struct SignedWithPreviousKey;
+
+impl RotatingCookiePolicy {
+    pub fn new(config: &CookieConfig) -> Self {
+        let previous = match (&config.previous_key, config.previous_key_until) {
+            (Some(key), Some(until)) => Some((cookie_policy(key, config), until)),
+            _ => None,
+        };
+
+        RotatingCookiePolicy {
+            current: cookie_policy(&config.key, config),
+            previous,
+        }
+    }
+}
+
+// Both keys write the same cookie with the same flags, only the key differs.
+fn cookie_policy(key: &[u8], config: &CookieConfig) -> CookieIdentityPolicy {
+    let policy = CookieIdentityPolicy::new(key)
+        // This means that when a request comes in, it grabs the "auth-cookie"
+        // and does a look up to see what the corresponding id should be. This
+        // is the value set inside the .remember() function in the
+        // process_login function.
+        .name("auth-cookie")
+        .secure(config.secure)
+        .same_site(config.same_site);
+
+    match config.max_age {
+        Some(seconds) => policy.max_age(seconds),
+        None => policy,
+    }
+}
+
+impl IdentityPolicy for RotatingCookiePolicy {
+    type Future = <CookieIdentityPolicy as IdentityPolicy>::Future;
+    type ResponseFuture = <CookieIdentityPolicy as IdentityPolicy>::ResponseFuture;
+
+    fn from_request(&self, request: &mut ServiceRequest) -> Self::Future {
+        // The cookie policy only ever hands back a ready future, so we can look
+        // inside it to see whether the current key could read the cookie.
+        let current = self.current.from_request(request).into_inner();
+
+        match (current, &self.previous) {
+            (Ok(None), Some((previous, until))) if chrono::Utc::now().naive_utc() < *until => {
+                let identity = previous.from_request(request).into_inner();
+                if let Ok(Some(_)) = identity {
+                    request.extensions_mut().insert(SignedWithPreviousKey);
+                }
+                futures_util::future::ready(identity)
+            }
+            (current, _) => futures_util::future::ready(current),
+        }
+    }
+
+    fn to_response<B>(&self, identity: Option<String>, changed: bool,
+                      response: &mut ServiceResponse<B>) -> Self::ResponseFuture {
+        let stale = response.request().extensions().contains::<SignedWithPreviousKey>();
+        self.current.to_response(identity, changed || stale, response)
+    }
+}
+
+pub struct SessionPolicy {
+    cookie: RotatingCookiePolicy,
+}
+
+// The token the request's cookie held, whether or not its session was still
+// around.
+#[derive(Clone)]
+struct CookieToken(String);
+
+impl SessionPolicy {
+    pub fn new(config: &CookieConfig) -> Self {
+        SessionPolicy {
+            cookie: RotatingCookiePolicy::new(config),
+        }
+    }
+}
+
+impl IdentityPolicy for SessionPolicy {
+    type Future = LocalBoxFuture<'static, Result<Option<String>, Error>>;
+    type ResponseFuture = LocalBoxFuture<'static, Result<(), Error>>;
+
+    // Looks the token up and marks the session as seen just now. A token
+    // without a session, because it was logged out or kicked, counts as not
+    // logged in at all.
+    // Requests with an Authorization header are using an API token instead
+    // (see auth.rs), and skip the CSRF check because of it, so any session
+    // cookie that came along with them is ignored.
+    fn from_request(&self, request: &mut ServiceRequest) -> Self::Future {
+        if request.headers().contains_key(header::AUTHORIZATION) {
+            return futures_util::future::ready(Ok(None)).boxed_local();
+        }
+
+        let token = match self.cookie.from_request(request).into_inner() {
+            Ok(Some(token)) => token,
+            other => return futures_util::future::ready(other).boxed_local(),
+        };
+        request.extensions_mut().insert(CookieToken(token.clone()));
+
+        let pool = request.app_data::<web::Data<Pool>>().cloned();
+
+        async move {
+            let pool = match pool {
+                Some(pool) => pool,
+                None => return Ok(None),
+            };
+
+            let session = web::block(move || -> Result<Option<Session>, AppError> {
+                use schema::sessions::dsl::{sessions, token as session_token, last_seen_at};
+
+                let connection = pool.get()?;
+                Ok(diesel::update(sessions.filter(session_token.eq(&token)))
+                    .set(last_seen_at.eq(chrono::Utc::now().naive_utc()))
+                    .get_result(&connection)
+                    .optional()?)
+            }).await.map_err(AppError::from)?;
+
+            Ok(session.map(|s| s.token))
+        }.boxed_local()
+    }
+
+    // Calling id.forget() ends the session for good, and so does logging in
+    // again over the top of it. If the cookie pointed at a session that is
+    // already gone we clear it so the browser stops sending it.
+    fn to_response<B>(&self, identity: Option<String>, changed: bool,
+                      response: &mut ServiceResponse<B>) -> Self::ResponseFuture {
+        let old = response.request().extensions().get::<CookieToken>().cloned();
+
+        let dead_cookie = !changed && identity.is_none() && old.is_some();
+        let written = self.cookie
+            .to_response(identity.clone(), changed || dead_cookie, response)
+            .into_inner();
+
+        let revoked = match old {
+            Some(CookieToken(old)) if changed && identity.as_ref() != Some(&old) => old,
+            _ => return futures_util::future::ready(written).boxed_local(),
+        };
+        let pool = response.request().app_data::<web::Data<Pool>>().cloned();
+
+        async move {
+            written?;
+
+            if let Some(pool) = pool {
+                web::block(move || -> Result<usize, AppError> {
+                    use schema::sessions::dsl::{sessions, token};
+
+                    let connection = pool.get()?;
+                    Ok(diesel::delete(sessions.filter(token.eq(revoked))).execute(&connection)?)
+                }).await.map_err(AppError::from)?;
+            }
+            Ok(())
+        }.boxed_local()
+    }
+}
@@ -0,0 +1,158 @@
+// The front page can be sorted a few different ways. All of the sorting and
+// paging is done by postgres so we only ever pull back the posts we show.
+// The hot ranking is the hot() function created in the ranked_listings
+// migration, and the score it uses is kept up to date by a trigger on votes.
+use diesel::prelude::*;
+use diesel::dsl::not;
+use diesel::pg::PgConnection;
+use diesel::sql_types::{Integer, Timestamp, Double};
+use super::models::{Post, User};
+use super::schema::{posts, users};
+use super::pagination::{Direction, Page};
+
+sql_function!(fn hot(score: Integer, created_at: Timestamp) -> Double);
+
+// Which posts the top listing looks at, by how long ago they were submitted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TopRange {
+    Day,
+    Week,
+    Month,
+    All,
+}
+
+impl TopRange {
+    // Reads the ?t= value from the url. Anything we don't recognise falls back
+    // to the last day, the same as when no range is given at all.
+    pub fn from_query(t: Option<&str>) -> Self {
+        match t {
+            Some("week") => TopRange::Week,
+            Some("month") => TopRange::Month,
+            Some("all") => TopRange::All,
+            _ => TopRange::Day,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TopRange::Day => "day",
+            TopRange::Week => "week",
+            TopRange::Month => "month",
+            TopRange::All => "all",
+        }
+    }
+
+    // The oldest a post can be to show up in this range, None means forever.
+    pub fn since(&self, now: chrono::NaiveDateTime) -> Option<chrono::NaiveDateTime> {
+        match self {
+            TopRange::Day => Some(now - chrono::Duration::days(1)),
+            TopRange::Week => Some(now - chrono::Duration::weeks(1)),
+            TopRange::Month => Some(now - chrono::Duration::days(30)),
+            TopRange::All => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Listing {
+    Hot,
+    New,
+    Top(TopRange),
+}
+
+impl Listing {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Listing::Hot => "hot",
+            Listing::New => "new",
+            Listing::Top(_) => "top",
+        }
+    }
+
+    // Where the feed of this listing is, format being rss or atom.
+    pub fn feed_url(&self, format: &str) -> String {
+        match self {
+            Listing::Hot => format!("/{}", format),
+            Listing::New => format!("/new/{}", format),
+            Listing::Top(range) => format!("/top/{}?t={}", format, range.name()),
+        }
+    }
+
+    // Loads one page of posts for this listing along with their authors, best
+    // first. Every ordering ends with the post id so posts that tie always come
+    // back in the same order, which is what lets us page by cursor.
+    // The cursor is a post id; we look that post up and ask for the posts that
+    // sort after it (or before it, when going back a page). Paging backwards
+    // loads the posts in reverse order, Page::from_rows turns them around.
+    // If the cursor post has been deleted we start again from the first page.
+    pub fn load(&self, connection: &PgConnection, direction: Direction,
+                page_size: i64) -> QueryResult<Page<(Post, User)>> {
+        let cursor: Option<Post> = match direction {
+            Direction::After(id) | Direction::Before(id) => posts::table.find(id)
+                .first(connection)
+                .optional()?,
+            Direction::First => None,
+        };
+        let direction = if cursor.is_some() { direction } else { Direction::First };
+        let backwards = matches!(direction, Direction::Before(_));
+
+        // Deleted posts keep their page, but they don't show up in listings.
+        let mut query = posts::table.inner_join(users::table)
+            .filter(posts::deleted_at.is_null())
+            .into_boxed();
+
+        if let Listing::Top(range) = self {
+            if let Some(since) = range.since(chrono::Local::now().naive_utc()) {
+                query = query.filter(posts::created_at.ge(since));
+            }
+        }
+
+        // Keeps the rows that come after the cursor post in listing order, or
+        // the ones that come before it when we are paging backwards. Because
+        // the id is always part of the sort, "before" is everything that is
+        // neither after the cursor nor the cursor itself.
+        macro_rules! past_cursor {
+            ($query:expr, $c:expr, $after:expr) => {
+                if backwards {
+                    $query.filter(not($after).and(posts::id.ne($c.id)))
+                } else {
+                    $query.filter($after)
+                }
+            };
+        }
+
+        let query = match (self, cursor) {
+            (Listing::Hot, Some(c)) => past_cursor!(query, c,
+                hot(posts::score, posts::created_at).lt(hot(c.score, c.created_at))
+                    .or(hot(posts::score, posts::created_at).eq(hot(c.score, c.created_at))
+                        .and(posts::id.lt(c.id)))),
+            (Listing::New, Some(c)) => past_cursor!(query, c,
+                posts::created_at.lt(c.created_at)
+                    .or(posts::created_at.eq(c.created_at).and(posts::id.lt(c.id)))),
+            (Listing::Top(_), Some(c)) => past_cursor!(query, c,
+                posts::score.lt(c.score)
+                    .or(posts::score.eq(c.score).and(posts::created_at.lt(c.created_at)))
+                    .or(posts::score.eq(c.score).and(posts::created_at.eq(c.created_at))
+                        .and(posts::id.lt(c.id)))),
+            (_, None) => query,
+        };
+
+        let query = match (self, backwards) {
+            (Listing::Hot, false) => query
+                .order((hot(posts::score, posts::created_at).desc(), posts::id.desc())),
+            (Listing::Hot, true) => query
+                .order((hot(posts::score, posts::created_at).asc(), posts::id.asc())),
+            (Listing::New, false) => query
+                .order((posts::created_at.desc(), posts::id.desc())),
+            (Listing::New, true) => query
+                .order((posts::created_at.asc(), posts::id.asc())),
+            (Listing::Top(_), false) => query
+                .order((posts::score.desc(), posts::created_at.desc(), posts::id.desc())),
+            (Listing::Top(_), true) => query
+                .order((posts::score.asc(), posts::created_at.asc(), posts::id.asc())),
+        };
+
+        let rows: Vec<(Post, User)> = query.limit(page_size + 1).load(connection)?;
+        Ok(Page::from_rows(rows, page_size, direction, |(p, _)| p.id))
+    }
+}
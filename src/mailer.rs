@@ -0,0 +1,99 @@
+// Sending email. Handlers put together an Email and hand it to the Mailer,
+// which is shared with them through the App's data like the pool. Which Mailer
+// that is comes from the MAILER setting: SMTP for real, or for development and
+// tests one that writes each email to a file or just logs it.
+//
+// Sending over SMTP blocks, so like database work it has to happen inside
+// web::block.
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::{Message, SmtpTransport, Transport};
+use super::config::{MailConfig, MailTransport};
+
+pub type MailError = Box<dyn std::error::Error + Send + Sync>;
+
+// A plain text email to one person.
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), MailError>;
+}
+
+pub fn from_config(config: &MailConfig) -> Result<Arc<dyn Mailer>, MailError> {
+    let from: Mailbox = config.from.parse()?;
+
+    Ok(match &config.transport {
+        MailTransport::Smtp(url) => Arc::new(SmtpMailer {
+            transport: SmtpTransport::from_url(url)?.build(),
+            from,
+        }),
+        MailTransport::File(dir) => {
+            fs::create_dir_all(dir)?;
+            Arc::new(FileMailer { dir: dir.clone(), from })
+        }
+        MailTransport::Log => Arc::new(LogMailer { from }),
+    })
+}
+
+fn message(from: &Mailbox, email: &Email) -> Result<Message, MailError> {
+    Ok(Message::builder()
+        .from(from.clone())
+        .to(email.to.parse()?)
+        .subject(email.subject.as_str())
+        .header(ContentType::TEXT_PLAIN)
+        .body(email.body.clone())?)
+}
+
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        self.transport.send(&message(&self.from, email)?)?;
+        Ok(())
+    }
+}
+
+// Writes every email to its own .eml file, which most mail programs can open.
+// The names start with the time so they sort in the order they were sent.
+pub struct FileMailer {
+    pub dir: PathBuf,
+    pub from: Mailbox,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        use rand::Rng;
+
+        let name = format!("{}-{:08x}.eml",
+                           chrono::Utc::now().format("%Y%m%dT%H%M%S%.6f"),
+                           rand::thread_rng().gen::<u32>());
+        let path = self.dir.join(name);
+        fs::write(&path, message(&self.from, email)?.formatted())?;
+        log::info!("Wrote email to {} to {}", email.to, path.display());
+        Ok(())
+    }
+}
+
+// Only logs the email, links and all. That's handy on a developer's machine
+// and nowhere else, since anyone who can read the log can use the links.
+pub struct LogMailer {
+    pub from: Mailbox,
+}
+
+impl Mailer for LogMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        log::info!("Email from {} to {}: {}\n{}", self.from, email.to, email.subject, email.body);
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+// Comments and the bodies of text posts are written in markdown. We render
+// them to HTML once, when they are saved, and keep the result in the
+// comment_html and body_html columns so showing a page doesn't mean parsing
+// every comment on it again.
+//
+// Whatever people type ends up on other people's screens, so the HTML goes
+// through ammonia before we store it. Only the tags in ALLOWED_TAGS survive,
+// and links only to http, https or mailto. Raw HTML in the markdown is shown
+// as the text it is rather than passed through. Bare urls are turned into
+// links, and every link gets rel="nofollow ugc" so search engines know they
+// were added by users and not vouched for by us.
+use std::collections::HashSet;
+use diesel::prelude::*;
+use linkify::{LinkFinder, LinkKind};
+use pulldown_cmark::{html, CowStr, Event, LinkType, Options, Parser, Tag};
+
+// Headings and images aren't in here on purpose: a heading in a comment only
+// shouts, and images would let anyone embed whatever they like in our pages.
+// The text inside a tag that isn't allowed is kept.
+const ALLOWED_TAGS: &[&str] = &[
+    "p", "br", "em", "strong", "del", "a", "code", "pre", "blockquote", "ul", "ol", "li", "hr",
+];
+
+const URL_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+pub fn render(source: &str) -> String {
+    let parser = Parser::new_ext(source, Options::ENABLE_STRIKETHROUGH);
+    let events = linkify(merge_text(parser.map(escape_html)));
+
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, events.into_iter());
+
+    ammonia::Builder::default()
+        .tags(ALLOWED_TAGS.iter().copied().collect::<HashSet<_>>())
+        .url_schemes(URL_SCHEMES.iter().copied().collect::<HashSet<_>>())
+        .link_rel(Some("nofollow ugc"))
+        .clean(&unsafe_html)
+        .to_string()
+}
+
+// Raw HTML is shown as text instead of being dropped, so someone writing about
+// a <div> in their comment still sees it. Images become plain links to the
+// image.
+fn escape_html(event: Event) -> Event {
+    match event {
+        Event::Html(html) => Event::Text(html),
+        Event::Start(Tag::Image(kind, url, title)) => Event::Start(Tag::Link(kind, url, title)),
+        Event::End(Tag::Image(kind, url, title)) => Event::End(Tag::Link(kind, url, title)),
+        event => event,
+    }
+}
+
+// The parser hands back text in pieces wherever a character might have
+// started some markup, like the _ in a url. We join them back together so
+// linkify sees whole urls.
+fn merge_text<'a>(events: impl Iterator<Item = Event<'a>>) -> Vec<Event<'a>> {
+    let mut merged: Vec<Event> = Vec::new();
+
+    for event in events {
+        match (merged.last_mut(), event) {
+            (Some(Event::Text(previous)), Event::Text(text)) => {
+                *previous = CowStr::from(format!("{}{}", previous, text));
+            }
+            (_, event) => merged.push(event),
+        }
+    }
+
+    merged
+}
+
+// Turns bare urls in the text into links. Text that is already inside a link
+// or a code block is left alone.
+fn linkify(events: Vec<Event>) -> Vec<Event> {
+    let mut finder = LinkFinder::new();
+    finder.kinds(&[LinkKind::Url]);
+
+    let mut linked = Vec::with_capacity(events.len());
+    let mut inside = 0;
+
+    for event in events {
+        match event {
+            Event::Start(Tag::Link(..)) | Event::Start(Tag::CodeBlock(_)) => {
+                inside += 1;
+                linked.push(event);
+            }
+            Event::End(Tag::Link(..)) | Event::End(Tag::CodeBlock(_)) => {
+                inside -= 1;
+                linked.push(event);
+            }
+            Event::Text(text) if inside == 0 => {
+                let mut last = 0;
+                for link in finder.links(&text) {
+                    if link.start() > last {
+                        linked.push(Event::Text(CowStr::from(text[last..link.start()].to_string())));
+                    }
+                    let url = CowStr::from(link.as_str().to_string());
+                    let tag = Tag::Link(LinkType::Autolink, url.clone(), CowStr::Borrowed(""));
+                    linked.push(Event::Start(tag.clone()));
+                    linked.push(Event::Text(url));
+                    linked.push(Event::End(tag));
+                    last = link.end();
+                }
+                if last < text.len() {
+                    linked.push(Event::Text(CowStr::from(text[last..].to_string())));
+                }
+            }
+            event => linked.push(event),
+        }
+    }
+
+    linked
+}
+
+// Renders anything that hasn't been rendered yet: rows from before we had
+// markdown, or ones an admin set back to NULL after the rules here changed.
+// Called once when the server starts.
+pub fn backfill(connection: &PgConnection) -> QueryResult<usize> {
+    use super::schema::{comments, posts};
+
+    let mut count = 0;
+
+    let unrendered: Vec<(i32, String)> = comments::table
+        .select((comments::id, comments::comment))
+        .filter(comments::comment_html.is_null())
+        .load(connection)?;
+    for (id, comment) in unrendered {
+        count += diesel::update(comments::table.find(id))
+            .set(comments::comment_html.eq(render(&comment)))
+            .execute(connection)?;
+    }
+
+    let unrendered: Vec<(i32, Option<String>)> = posts::table
+        .select((posts::id, posts::body))
+        .filter(posts::body.is_not_null().and(posts::body_html.is_null()))
+        .load(connection)?;
+    for (id, body) in unrendered {
+        count += diesel::update(posts::table.find(id))
+            .set(posts::body_html.eq(body.as_deref().map(render)))
+            .execute(connection)?;
+    }
+
+    Ok(count)
+}
@@ -1,10 +1,13 @@
 // We use the schema.rs file via the super option because the models.rs file is
 // under the root, main.rs file.
-use super::schema::{users, posts,comments};
+use super::schema::{users, posts, comments, votes, sessions, api_tokens, password_resets,
+                    recovery_codes, username_redirects, account_deletions};
+use super::markdown;
 use diesel::{Queryable, Insertable};
 use serde::{Serialize,Deserialize};
 use crate::dotenv;
-use argonautica::Hasher;
+use argonautica::{Hasher, Verifier};
+use std::collections::HashMap;
 // We are exposing our structs to other parts of our application through the pub
 // keyword.  We can also keep things private if we need to.
 
@@ -17,6 +20,66 @@ pub struct Comment {
     pub user_id: i32,
     pub parent_comment_id: Option<i32>,
     pub created_at: chrono::NaiveDateTime,
+    pub edited_at: Option<chrono::NaiveDateTime>,
+    // Deleted comments stay in the thread so their replies still have a
+    // parent, the templates show [deleted] instead of the text.
+    pub deleted_at: Option<chrono::NaiveDateTime>,
+    // The comment rendered from markdown, see markdown.rs.
+    pub comment_html: Option<String>,
+}
+
+// Comments are loaded from the database as a flat list, but replies point
+// back at the comment they answer through parent_comment_id. A CommentNode is
+// one comment along with its author and all of the replies underneath it, so
+// the post page can render the discussion as a nested thread.
+// The depth is how far the comment is indented, with top level comments at 0.
+#[derive(Debug, Serialize)]
+pub struct CommentNode {
+    pub comment: Comment,
+    pub user: User,
+    pub depth: usize,
+    pub replies: Vec<CommentNode>,
+}
+
+impl CommentNode {
+    // Turns the flat (Comment, User) rows for a post into a list of top level
+    // threads. The rows should already be sorted the way we want siblings to
+    // appear (oldest first). Threads are only nested up to max_depth, anything
+    // deeper than that is shown at max_depth right after the comment it
+    // replies to so long conversations don't run off the side of the page.
+    // A reply whose parent isn't in the rows is treated as a top level comment.
+    pub fn build_tree(rows: Vec<(Comment, User)>, max_depth: usize) -> Vec<CommentNode> {
+        let mut children: HashMap<Option<i32>, Vec<(Comment, User)>> = HashMap::new();
+        let ids: Vec<i32> = rows.iter().map(|(c, _)| c.id).collect();
+
+        for (comment, user) in rows {
+            let parent = comment.parent_comment_id.filter(|p| ids.contains(p));
+            children.entry(parent).or_insert_with(Vec::new).push((comment, user));
+        }
+
+        CommentNode::build_level(&mut children, None, 0, max_depth)
+    }
+
+    fn build_level(children: &mut HashMap<Option<i32>, Vec<(Comment, User)>>,
+                   parent: Option<i32>, depth: usize, max_depth: usize) -> Vec<CommentNode> {
+        let mut nodes = Vec::new();
+
+        for (comment, user) in children.remove(&parent).unwrap_or_default() {
+            let comment_id = comment.id;
+
+            if depth < max_depth {
+                let replies = CommentNode::build_level(children, Some(comment_id), depth + 1, max_depth);
+                nodes.push(CommentNode { comment, user, depth, replies });
+            } else {
+                nodes.push(CommentNode { comment, user, depth, replies: Vec::new() });
+                // We've run out of room to indent, so the rest of this thread
+                // gets flattened out underneath as siblings.
+                nodes.extend(CommentNode::build_level(children, Some(comment_id), depth, max_depth));
+            }
+        }
+
+        nodes
+    }
 }
 
 #[derive(Serialize, Insertable)]
@@ -27,12 +90,14 @@ pub struct NewComment {
     pub user_id: i32,
     pub parent_comment_id: Option<i32>,
     pub created_at: chrono::NaiveDateTime,
+    pub comment_html: Option<String>,
 }
 
 impl NewComment {
     pub fn new(comment: String, post_id: i32,
                user_id: i32, parent_comment_id: Option<i32>) -> Self{
         NewComment {
+            comment_html: Some(markdown::render(&comment)),
             comment: comment,
             post_id: post_id,
             user_id: user_id,
@@ -52,12 +117,36 @@ impl NewComment {
 // the User table and get everything structure using the User struct.
 // Note: Because the struct has the Queryable trait, we need to make sure the
 // order and types match what is in the schema.
-#[derive(Serialize, Queryable, Debug)]
+#[derive(Serialize, Queryable, Identifiable, Debug)]
 pub struct User {
     pub id: i32,
     pub username: String,
     pub email: String,
+    // The argon2 hash. Templates get the whole User, so this is kept out of
+    // them the same as the two-factor secret.
+    #[serde(skip_serializing)]
     pub password: String,
+    pub created_at: chrono::NaiveDateTime,
+    // None until the user follows the link in the verification email.
+    pub email_verified_at: Option<chrono::NaiveDateTime>,
+    // The secret shared with the user's authenticator app, see twofactor.rs.
+    // It's as good as a second password, so it never goes out to a template.
+    #[serde(skip_serializing)]
+    pub totp_secret: Option<String>,
+    // None unless two-factor authentication is turned on.
+    pub totp_enabled_at: Option<chrono::NaiveDateTime>,
+    #[serde(skip_serializing)]
+    pub totp_last_step: Option<i64>,
+}
+
+impl User {
+    pub fn is_verified(&self) -> bool {
+        self.email_verified_at.is_some()
+    }
+
+    pub fn has_two_factor(&self) -> bool {
+        self.totp_enabled_at.is_some()
+    }
 }
 
 // To extract the data we need to be able to take the string
@@ -79,24 +168,47 @@ pub struct NewUser {
 }
 
 impl NewUser {
-    pub fn new(username: String, email: String, password: String) -> Self {
-        dotenv().ok();
+    // Hashing can fail, in which case the caller gets the argonautica error
+    // back instead of a user with no password.
+    pub fn new(username: String, email: String, password: String) -> Result<Self, argonautica::Error> {
+        Ok(NewUser {
+            username: username,
+            email: email,
+            password: hash_password(password)?,
+        })
+    }
+}
 
-        let secret = std::env::var("SECRET_KEY")
-            .expect("SECRET_KEY must be set");
+// Hashes a password with our SECRET_KEY, for a new user or a new password.
+pub fn hash_password(password: String) -> Result<String, argonautica::Error> {
+    dotenv().ok();
 
-        let hash = Hasher::default()
-            .with_password(password)
-            .with_secret_key(secret)
-            .hash()
-            .unwrap();
+    let secret = std::env::var("SECRET_KEY")
+        .expect("SECRET_KEY must be set");
 
-        NewUser {
-            username: username,
-            email: email,
-            password: hash,
-        }
+    Hasher::default()
+        .with_password(password)
+        .with_secret_key(secret)
+        .hash()
+}
+
+// Whether the password is the one the hash was made from. Accounts nobody
+// can log in to, like the [deleted] user, have an empty hash, which nothing
+// matches.
+pub fn verify_password(hash: &str, password: String) -> Result<bool, argonautica::Error> {
+    if hash.is_empty() {
+        return Ok(false);
     }
+    dotenv().ok();
+
+    let secret = std::env::var("SECRET_KEY")
+        .expect("SECRET_KEY must be set");
+
+    Verifier::default()
+        .with_hash(hash)
+        .with_password(password)
+        .with_secret_key(secret)
+        .verify()
 }
 
 // This also derives Debug so we can print the data out.
@@ -114,6 +226,8 @@ pub(crate) struct LoginUser {
 // chrono crate. These types aren't included in serde so if we didn't
 // enable serde in our chrono crate we would have issues with the
 // Serialization and Deserialization traits.
+// The score is the sum of the votes on the post. It's kept up to date by a
+// trigger on the votes table so we never write it ourselves.
 #[derive(Serialize, Debug, Queryable, Identifiable)]
 pub struct Post {
     pub id: i32,
@@ -121,6 +235,15 @@ pub struct Post {
     pub link: Option<String>,
     pub author: i32,
     pub created_at: chrono::NaiveDateTime,
+    pub score: i32,
+    // The text of a text post. A post has a link, a body or both.
+    pub body: Option<String>,
+    pub edited_at: Option<chrono::NaiveDateTime>,
+    // Deleted posts drop out of the listings, but their page and discussion
+    // are still there with [deleted] in place of the post.
+    pub deleted_at: Option<chrono::NaiveDateTime>,
+    // The body rendered from markdown, see markdown.rs.
+    pub body_html: Option<String>,
 }
 
 // NewPost struct contains all the fields we want to set when we go to insert
@@ -133,20 +256,293 @@ pub struct Post {
 #[table_name="posts"]
 pub struct NewPost {
     pub title: String,
-    pub link: String,
+    pub link: Option<String>,
     pub author: i32,
     pub created_at: chrono::NaiveDateTime,
+    pub body: Option<String>,
+    pub body_html: Option<String>,
 }
 
-// This creates a function that will build a NewPost object from a title, link
-// and user id we pass in.
+// This creates a function that will build a NewPost object from a title, link,
+// body and user id we pass in.
 impl NewPost{
-    pub fn from_post_form(title: String, link: String, uid: i32) -> Self {
+    pub fn from_post_form(title: String, link: String, body: String, uid: i32) -> Self {
+        let content = PostContent::from_post_form(title, link, body);
         NewPost {
-            title: title,
-            link: link,
+            title: content.title,
+            link: content.link,
             author: uid,
             created_at: chrono::Local::now().naive_utc(),
+            body: content.body,
+            body_html: content.body_html,
         }
     }
-}
\ No newline at end of file
+}
+
+// The parts of a post that come from the form, tidied up the way we store
+// them. New posts and edits both go through this. The form sends an empty link
+// or body when that box was left empty, which we store as NULL, so an edit
+// that empties one has to set it to NULL rather than leave it alone.
+#[derive(AsChangeset)]
+#[table_name="posts"]
+#[changeset_options(treat_none_as_null="true")]
+pub struct PostContent {
+    pub title: String,
+    pub link: Option<String>,
+    pub body: Option<String>,
+    pub body_html: Option<String>,
+}
+
+impl PostContent {
+    pub fn from_post_form(title: String, link: String, body: String) -> Self {
+        let body = non_empty(body);
+        PostContent {
+            title: title.trim().to_string(),
+            link: non_empty(link),
+            body_html: body.as_deref().map(markdown::render),
+            body,
+        }
+    }
+}
+
+fn non_empty(value: String) -> Option<String> {
+    let trimmed = value.trim();
+    if trimmed.is_empty() { None } else { Some(trimmed.to_string()) }
+}
+
+// A Vote is one user's up (1) or down (-1) vote on a post. The votes table
+// only allows one row per user and post, so voting again changes the value of
+// the existing vote.
+#[derive(Debug, Serialize, Queryable, Identifiable, Associations)]
+#[belongs_to(Post)]
+pub struct Vote {
+    pub id: i32,
+    pub post_id: i32,
+    pub user_id: i32,
+    pub value: i16,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name="votes"]
+pub struct NewVote {
+    pub post_id: i32,
+    pub user_id: i32,
+    pub value: i16,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl NewVote {
+    pub fn new(post_id: i32, user_id: i32, value: i16) -> Self {
+        NewVote {
+            post_id: post_id,
+            user_id: user_id,
+            value: value,
+            created_at: chrono::Local::now().naive_utc(),
+        }
+    }
+}
+
+// One logged in browser. The token is what the login cookie holds, so it is
+// as good as a password and never goes out to a template.
+#[derive(Debug, Serialize, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+pub struct Session {
+    pub id: i32,
+    #[serde(skip_serializing)]
+    pub token: String,
+    pub user_id: i32,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_seen_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name="sessions"]
+pub struct NewSession {
+    pub token: String,
+    pub user_id: i32,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl NewSession {
+    // Makes up a new random token for the session. 43 letters and digits is
+    // a little over 256 bits, far too many to guess.
+    pub fn new(user_id: i32, user_agent: Option<String>, ip: Option<String>) -> Self {
+        use rand::Rng;
+        use rand::distributions::Alphanumeric;
+
+        let token = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(43)
+            .collect();
+
+        NewSession {
+            token,
+            user_id,
+            user_agent,
+            ip,
+        }
+    }
+}
+
+// A personal API token. Like a session token it's as good as a password for
+// whatever its scopes allow, so we only keep its hash, and that never goes out
+// to a template either.
+#[derive(Debug, Serialize, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_used_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name="api_tokens"]
+pub struct NewApiToken {
+    pub user_id: i32,
+    pub name: String,
+    pub token_hash: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+}
+
+impl NewApiToken {
+    // Makes up a new token and hands it back along with the row to store. The
+    // token itself is only ever shown to the user once, right after this.
+    pub fn new(user_id: i32, name: String, scopes: Vec<String>) -> (Self, String) {
+        use rand::Rng;
+        use rand::distributions::Alphanumeric;
+
+        let secret: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(43)
+            .collect();
+        let token = format!("oasis_{}", secret);
+
+        let new_token = NewApiToken {
+            user_id,
+            name,
+            token_hash: hash_token(&token),
+            prefix: token[..12].to_string(),
+            scopes,
+        };
+        (new_token, token)
+    }
+}
+
+// A password reset token waiting to be used. It's only good until expires_at,
+// and is deleted as soon as the password has been changed.
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+pub struct PasswordReset {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub expires_at: chrono::NaiveDateTime,
+}
+
+impl PasswordReset {
+    pub fn is_expired(&self, now: chrono::NaiveDateTime) -> bool {
+        self.expires_at <= now
+    }
+}
+
+#[derive(Insertable)]
+#[table_name="password_resets"]
+pub struct NewPasswordReset {
+    pub user_id: i32,
+    pub token_hash: String,
+    pub expires_at: chrono::NaiveDateTime,
+}
+
+impl NewPasswordReset {
+    // Makes up a token that lasts for the given number of seconds and hands it
+    // back along with the row to store. The token itself only goes out in the
+    // email.
+    pub fn new(user_id: i32, lifetime: i64) -> (Self, String) {
+        use rand::Rng;
+        use rand::distributions::Alphanumeric;
+
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(43)
+            .collect();
+
+        let reset = NewPasswordReset {
+            user_id,
+            token_hash: hash_token(&token),
+            expires_at: chrono::Utc::now().naive_utc() + chrono::Duration::seconds(lifetime),
+        };
+        (reset, token)
+    }
+}
+
+// A recovery code for logging in without the authenticator app. Only the
+// hash of the code is kept, and the row is deleted once it has been used.
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+pub struct RecoveryCode {
+    pub id: i32,
+    pub user_id: i32,
+    pub code_hash: String,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name="recovery_codes"]
+pub struct NewRecoveryCode {
+    pub user_id: i32,
+    pub code_hash: String,
+}
+
+impl NewRecoveryCode {
+    // The rows for a fresh set of codes, along with the codes themselves to
+    // show the user once.
+    pub fn generate(user_id: i32) -> (Vec<Self>, Vec<String>) {
+        let codes = super::twofactor::new_recovery_codes();
+        let rows = codes.iter()
+            .map(|code| NewRecoveryCode {
+                user_id,
+                code_hash: hash_token(&super::twofactor::normalize_recovery_code(code)),
+            })
+            .collect();
+        (rows, codes)
+    }
+}
+
+// A name the user had before renaming their account, see the
+// username_redirects migration.
+#[derive(Insertable)]
+#[table_name="username_redirects"]
+pub struct NewUsernameRedirect {
+    pub username: String,
+    pub user_id: i32,
+}
+
+// An account waiting to be deleted, see accounts.rs. mode is "anonymize" or
+// "erase".
+#[derive(Debug, Serialize, Queryable, Insertable)]
+#[table_name="account_deletions"]
+pub struct AccountDeletion {
+    pub user_id: i32,
+    pub mode: String,
+    pub requested_at: chrono::NaiveDateTime,
+    pub delete_at: chrono::NaiveDateTime,
+}
+
+// Tokens are long and random, so unlike passwords they don't need a slow hash
+// to be safe from guessing. A plain SHA-256 lets us look them up by hash.
+pub fn hash_token(token: &str) -> String {
+    use sha2::{Digest, Sha256};
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
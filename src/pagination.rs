@@ -0,0 +1,82 @@
+// Listings are paginated with a cursor instead of page numbers. The cursor is
+// the id of the first or last row on the page the user is looking at, and the
+// next page is everything that sorts after that row. Unlike ?page=2, rows
+// don't repeat or go missing when new posts are added while someone is paging
+// through, and postgres can jump straight to the cursor using an index instead
+// of counting past every row before it.
+use serde::Deserialize;
+
+// The ?after= and ?before= values from the url. Only one should be given,
+// if both are we go with after.
+#[derive(Debug, Default, Deserialize)]
+pub struct Cursor {
+    pub after: Option<i32>,
+    pub before: Option<i32>,
+}
+
+impl Cursor {
+    pub fn direction(&self) -> Direction {
+        match (self.after, self.before) {
+            (Some(id), _) => Direction::After(id),
+            (None, Some(id)) => Direction::Before(id),
+            (None, None) => Direction::First,
+        }
+    }
+}
+
+// Which way we are paging relative to the cursor row.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    First,
+    After(i32),
+    Before(i32),
+}
+
+// One page of rows, along with the cursors for the pages either side of it.
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<i32>,
+    pub prev: Option<i32>,
+}
+
+impl<T> Page<T> {
+    // Queries load one more row than the page size so we can tell whether
+    // there's anything past this page without a separate count. When paging
+    // backwards the rows come back in reverse order, so we flip them back
+    // around before working out the cursors.
+    pub fn from_rows<F>(mut rows: Vec<T>, page_size: i64, direction: Direction, key: F) -> Page<T>
+        where F: Fn(&T) -> i32 {
+        let has_more = rows.len() as i64 > page_size;
+        rows.truncate(page_size.max(0) as usize);
+
+        if let Direction::Before(_) = direction {
+            rows.reverse();
+        }
+
+        let first = rows.first().map(|r| key(r));
+        let last = rows.last().map(|r| key(r));
+
+        let (next, prev) = match direction {
+            Direction::First => (if has_more { last } else { None }, None),
+            Direction::After(_) => (if has_more { last } else { None }, first),
+            Direction::Before(_) => (last, if has_more { first } else { None }),
+        };
+
+        Page { items: rows, next, prev }
+    }
+
+    pub fn next_url(&self, base: &str) -> Option<String> {
+        self.next.map(|id| with_cursor(base, "after", id))
+    }
+
+    pub fn prev_url(&self, base: &str) -> Option<String> {
+        self.prev.map(|id| with_cursor(base, "before", id))
+    }
+}
+
+// Adds the cursor to a url that may already have its own query string.
+fn with_cursor(base: &str, key: &str, id: i32) -> String {
+    let separator = if base.contains('?') { '&' } else { '?' };
+    format!("{}{}{}={}", base, separator, key, id)
+}
@@ -1,3 +1,25 @@
+table! {
+    account_deletions (user_id) {
+        user_id -> Int4,
+        mode -> Varchar,
+        requested_at -> Timestamp,
+        delete_at -> Timestamp,
+    }
+}
+
+table! {
+    api_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Varchar,
+        token_hash -> Varchar,
+        prefix -> Varchar,
+        scopes -> Array<Text>,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     comments (id) {
         id -> Int4,
@@ -6,6 +28,28 @@ table! {
         user_id -> Int4,
         parent_comment_id -> Nullable<Int4>,
         created_at -> Timestamp,
+        edited_at -> Nullable<Timestamp>,
+        deleted_at -> Nullable<Timestamp>,
+        comment_html -> Nullable<Text>,
+    }
+}
+
+table! {
+    login_attempts (key) {
+        key -> Varchar,
+        failures -> Int4,
+        last_failure_at -> Timestamp,
+        blocked_until -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    password_resets (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
     }
 }
 
@@ -16,6 +60,40 @@ table! {
         link -> Nullable<Varchar>,
         author -> Int4,
         created_at -> Timestamp,
+        score -> Int4,
+        body -> Nullable<Text>,
+        edited_at -> Nullable<Timestamp>,
+        deleted_at -> Nullable<Timestamp>,
+        body_html -> Nullable<Text>,
+    }
+}
+
+table! {
+    recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        code_hash -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Int4,
+        token -> Varchar,
+        user_id -> Int4,
+        user_agent -> Nullable<Varchar>,
+        ip -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+    }
+}
+
+table! {
+    username_redirects (username) {
+        username -> Varchar,
+        user_id -> Int4,
+        created_at -> Timestamp,
     }
 }
 
@@ -25,15 +103,46 @@ table! {
         username -> Varchar,
         email -> Varchar,
         password -> Varchar,
+        created_at -> Timestamp,
+        email_verified_at -> Nullable<Timestamp>,
+        totp_secret -> Nullable<Varchar>,
+        totp_enabled_at -> Nullable<Timestamp>,
+        totp_last_step -> Nullable<Int8>,
+    }
+}
+
+table! {
+    votes (id) {
+        id -> Int4,
+        post_id -> Int4,
+        user_id -> Int4,
+        value -> Int2,
+        created_at -> Timestamp,
     }
 }
 
+joinable!(account_deletions -> users (user_id));
+joinable!(api_tokens -> users (user_id));
 joinable!(comments -> posts (post_id));
 joinable!(comments -> users (user_id));
+joinable!(password_resets -> users (user_id));
 joinable!(posts -> users (author));
+joinable!(recovery_codes -> users (user_id));
+joinable!(sessions -> users (user_id));
+joinable!(username_redirects -> users (user_id));
+joinable!(votes -> posts (post_id));
+joinable!(votes -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    account_deletions,
+    api_tokens,
     comments,
+    login_attempts,
+    password_resets,
     posts,
+    recovery_codes,
+    sessions,
+    username_redirects,
     users,
+    votes,
 );
@@ -0,0 +1,170 @@
+// Full text search over post titles, bodies, link domains and comments. The
+// words of every post and comment are kept in their search_vector column by
+// triggers (see the search migration), and postgres does the matching and
+// ranking. Diesel doesn't know about tsvector, so the query is plain SQL.
+//
+// Results are ranked by how well they match, which means postgres has to score
+// every match before it knows which ones come first. So unlike the listings we
+// page with ?page=N: skipping rows costs nothing on top of that.
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use diesel::sql_types::{BigInt, Float4, Integer, Nullable, Text, Timestamp};
+use serde::{Deserialize, Serialize};
+
+// ts_headline marks the matching words with these. They're characters from
+// the private use area that nobody types, so once the snippet has been escaped
+// we can swap them for <mark> tags without letting any other HTML through.
+const MARK_START: char = '\u{E000}';
+const MARK_END: char = '\u{E001}';
+
+// What was asked for, straight from the url. Everything is optional, an empty
+// search just shows the form. The dates are YYYY-MM-DD and both days count.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    pub q: String,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub from: String,
+    #[serde(default)]
+    pub to: String,
+    #[serde(skip_serializing)]
+    pub page: Option<i64>,
+}
+
+// One matching post or comment. Comments come with the title of the post they
+// are on. The title and snippet are HTML, escaped and with the matching words
+// in <mark>.
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct SearchResult {
+    #[sql_type = "Text"]
+    pub kind: String,
+    #[sql_type = "Integer"]
+    pub id: i32,
+    #[sql_type = "Integer"]
+    pub post_id: i32,
+    #[sql_type = "Timestamp"]
+    pub created_at: NaiveDateTime,
+    #[sql_type = "Float4"]
+    pub rank: f32,
+    #[sql_type = "Text"]
+    pub username: String,
+    #[sql_type = "Text"]
+    pub title_html: String,
+    #[sql_type = "Text"]
+    pub snippet_html: String,
+}
+
+// The matches are found and ranked in hits, then cut down to one page, and
+// only the rows on that page get the (slow) ts_headline treatment.
+const SEARCH_SQL: &str = "
+WITH q AS (SELECT websearch_to_tsquery('english', $1) AS query),
+hits AS (
+    SELECT 'post' AS kind, p.id, p.id AS post_id, p.author AS user_id, p.created_at,
+           ts_rank(p.search_vector, q.query) AS rank
+    FROM posts p, q
+    WHERE p.search_vector @@ q.query AND p.deleted_at IS NULL
+    UNION ALL
+    SELECT 'comment', c.id, c.post_id, c.user_id, c.created_at,
+           ts_rank(c.search_vector, q.query)
+    FROM comments c JOIN posts p ON p.id = c.post_id, q
+    WHERE c.search_vector @@ q.query AND c.deleted_at IS NULL AND p.deleted_at IS NULL
+),
+page AS (
+    SELECT hits.* FROM hits JOIN users u ON u.id = hits.user_id
+    WHERE ($2::TEXT IS NULL OR lower(u.username) = lower($2))
+      AND ($3::TIMESTAMP IS NULL OR hits.created_at >= $3)
+      AND ($4::TIMESTAMP IS NULL OR hits.created_at < $4)
+    ORDER BY hits.rank DESC, hits.created_at DESC, hits.kind, hits.id
+    LIMIT $5 OFFSET $6
+)
+SELECT page.kind, page.id, page.post_id, page.created_at, page.rank, u.username,
+       ts_headline('english', p.title, q.query, $7) AS title_html,
+       ts_headline('english',
+                   CASE WHEN page.kind = 'post' THEN coalesce(p.body, p.link, '') ELSE c.comment END,
+                   q.query, $8) AS snippet_html
+FROM page
+    JOIN users u ON u.id = page.user_id
+    JOIN posts p ON p.id = page.post_id
+    LEFT JOIN comments c ON page.kind = 'comment' AND c.id = page.id,
+    q
+ORDER BY page.rank DESC, page.created_at DESC, page.kind, page.id
+";
+
+impl SearchQuery {
+    pub fn is_empty(&self) -> bool {
+        self.q.trim().is_empty()
+    }
+
+    pub fn page(&self) -> i64 {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    pub fn from_date(&self) -> Option<NaiveDate> {
+        parse_date(&self.from)
+    }
+
+    pub fn to_date(&self) -> Option<NaiveDate> {
+        parse_date(&self.to)
+    }
+
+    // The url for another page of the same search.
+    pub fn page_url(&self, page: i64) -> String {
+        let query = serde_urlencoded::to_string(&[
+            ("q", self.q.as_str()),
+            ("author", self.author.as_str()),
+            ("from", self.from.as_str()),
+            ("to", self.to.as_str()),
+        ]).unwrap_or_default();
+        format!("/search?{}&page={}", query, page)
+    }
+
+    // Loads the results for the current page, best match first. The bool says
+    // whether there are more on the next page.
+    pub fn load(&self, connection: &PgConnection, page_size: i64) -> QueryResult<(Vec<SearchResult>, bool)> {
+        let author = Some(self.author.trim()).filter(|a| !a.is_empty());
+        let since = self.from_date().map(|d| d.and_hms(0, 0, 0));
+        let until = self.to_date().and_then(|d| d.succ_opt()).map(|d| d.and_hms(0, 0, 0));
+        let snippet_options = format!(
+            "StartSel={}, StopSel={}, MaxWords=35, MinWords=15, MaxFragments=2, FragmentDelimiter=\" ... \"",
+            MARK_START, MARK_END);
+        let title_options = format!("StartSel={}, StopSel={}, HighlightAll=true", MARK_START, MARK_END);
+
+        let mut results: Vec<SearchResult> = diesel::sql_query(SEARCH_SQL)
+            .bind::<Text, _>(self.q.trim())
+            .bind::<Nullable<Text>, _>(author)
+            .bind::<Nullable<Timestamp>, _>(since)
+            .bind::<Nullable<Timestamp>, _>(until)
+            .bind::<BigInt, _>(page_size + 1)
+            .bind::<BigInt, _>((self.page() - 1).saturating_mul(page_size))
+            .bind::<Text, _>(title_options)
+            .bind::<Text, _>(snippet_options)
+            .load(connection)?;
+
+        let has_more = results.len() as i64 > page_size;
+        results.truncate(page_size.max(0) as usize);
+
+        for result in results.iter_mut() {
+            result.title_html = highlight(&result.title_html);
+            result.snippet_html = highlight(&result.snippet_html);
+        }
+
+        Ok((results, has_more))
+    }
+}
+
+// chrono reads years far beyond what postgres can store, so anything outside
+// four digit years is turned away like any other date that makes no sense.
+pub fn parse_date(value: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(value.trim(), "%Y-%m-%d").ok()
+        .filter(|d| (1..=9999).contains(&d.year()))
+}
+
+// Escapes a headline from postgres and turns its markers into <mark> tags.
+fn highlight(headline: &str) -> String {
+    tera::escape_html(headline)
+        .replace(MARK_START, "<mark>")
+        .replace(MARK_END, "</mark>")
+}
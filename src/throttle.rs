@@ -0,0 +1,298 @@
+// Slowing down password guessing. Every login attempt counts against the
+// username and against the address it came from. After a few failures the
+// next attempt has to wait, twice as long after every further failure, and
+// after enough of them the username or address is locked out for a while.
+// Until the wait is over process_login answers with a 429 and a Retry-After
+// header, before it gets as far as hashing the password, so hammering the
+// login form can't keep the CPU busy either.
+//
+// An attempt is counted as a failure before the password is checked, in the
+// same step as looking at whether it has to wait. Checking first and counting
+// afterwards would let any number of requests sent at once through before the
+// first of them had been counted. When the password turns out to be right the
+// attempt is taken back off again: a successful login clears the username's
+// failures, but only takes the one attempt off the address's, otherwise an
+// attacker with one account could keep resetting their own count.
+//
+// The counts live in an AttemptStore, in memory or in postgres depending on
+// LOGIN_THROTTLE_STORE. Like everything that talks to postgres the store is
+// used inside web::block.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use chrono::{Duration, NaiveDateTime};
+use diesel::prelude::*;
+use super::config::{ThrottleConfig, ThrottleStore};
+use super::errors::AppError;
+use super::schema::login_attempts;
+use super::Pool;
+
+// An address gets this many times the failures a username does.
+const IP_MULTIPLIER: i32 = 4;
+
+// The failures counted against one key so far.
+#[derive(Debug, Clone, PartialEq, Queryable, Insertable, AsChangeset)]
+#[table_name="login_attempts"]
+#[changeset_options(treat_none_as_null="true")]
+pub struct Attempts {
+    pub key: String,
+    pub failures: i32,
+    pub last_failure_at: NaiveDateTime,
+    pub blocked_until: Option<NaiveDateTime>,
+}
+
+impl Attempts {
+    // How many seconds are left to wait, if any. Partial seconds round up so
+    // a client that waits exactly that long isn't turned away again.
+    pub fn retry_after(&self, now: NaiveDateTime) -> Option<i64> {
+        let until = self.blocked_until?;
+        if until <= now {
+            return None;
+        }
+        Some(((until - now).num_milliseconds() + 999) / 1000)
+    }
+}
+
+// The numbers for one kind of key.
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    pub free_attempts: i32,
+    pub lockout_after: i32,
+    pub lockout_seconds: i64,
+    pub window: i64,
+}
+
+impl Policy {
+    // The count after one more failure. Failures from longer ago than the
+    // window don't count any more.
+    pub fn after_failure(&self, key: &str, previous: Option<Attempts>, now: NaiveDateTime) -> Attempts {
+        let failures = match previous {
+            Some(a) if now - a.last_failure_at < Duration::seconds(self.window) => a.failures + 1,
+            _ => 1,
+        };
+
+        let wait = if failures >= self.lockout_after {
+            Some(self.lockout_seconds)
+        } else if failures > self.free_attempts {
+            // 1, 2, 4, 8... seconds, never more than the lockout.
+            let doublings = (failures - self.free_attempts - 1).min(30) as u32;
+            Some((1i64 << doublings).min(self.lockout_seconds))
+        } else {
+            None
+        };
+
+        Attempts {
+            key: key.to_string(),
+            failures,
+            last_failure_at: now,
+            blocked_until: wait.map(|seconds| now + Duration::seconds(seconds)),
+        }
+    }
+
+    // The count with one attempt taken back off, or None if that was the only
+    // one. Any wait is kept if there are still enough failures for one.
+    pub fn after_refund(&self, attempts: Attempts) -> Option<Attempts> {
+        let failures = attempts.failures - 1;
+        if failures <= 0 {
+            return None;
+        }
+        Some(Attempts {
+            failures,
+            blocked_until: attempts.blocked_until.filter(|_| failures > self.free_attempts),
+            ..attempts
+        })
+    }
+}
+
+// record_attempt and refund each happen in one go, so requests coming in at
+// the same time can't both go by the same count.
+pub trait AttemptStore: Send + Sync {
+    // Counts another failure, unless the key still has to wait, in which case
+    // nothing is counted and it's a TooManyRequests.
+    fn record_attempt(&self, key: &str, policy: &Policy, now: NaiveDateTime) -> Result<Attempts, AppError>;
+    fn refund(&self, key: &str, policy: &Policy) -> Result<(), AppError>;
+    fn clear(&self, key: &str) -> Result<(), AppError>;
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    attempts: Mutex<HashMap<String, Attempts>>,
+}
+
+impl AttemptStore for MemoryStore {
+    fn record_attempt(&self, key: &str, policy: &Policy, now: NaiveDateTime) -> Result<Attempts, AppError> {
+        let mut attempts = self.attempts.lock().unwrap();
+        // Nothing else ever removes old entries, so forget the stale ones
+        // here to keep the map from growing forever.
+        let window = Duration::seconds(policy.window);
+        attempts.retain(|_, a| now - a.last_failure_at < window || a.blocked_until.map_or(false, |u| u > now));
+
+        if let Some(seconds) = attempts.get(key).and_then(|a| a.retry_after(now)) {
+            return Err(AppError::TooManyRequests(seconds));
+        }
+        let next = policy.after_failure(key, attempts.remove(key), now);
+        attempts.insert(key.to_string(), next.clone());
+        Ok(next)
+    }
+
+    fn refund(&self, key: &str, policy: &Policy) -> Result<(), AppError> {
+        let mut attempts = self.attempts.lock().unwrap();
+        if let Some(next) = attempts.remove(key).and_then(|a| policy.after_refund(a)) {
+            attempts.insert(key.to_string(), next);
+        }
+        Ok(())
+    }
+
+    fn clear(&self, key: &str) -> Result<(), AppError> {
+        self.attempts.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+pub struct PostgresStore {
+    pool: Pool,
+}
+
+impl AttemptStore for PostgresStore {
+    fn record_attempt(&self, key: &str, policy: &Policy, now: NaiveDateTime) -> Result<Attempts, AppError> {
+        use login_attempts::dsl::{last_failure_at, blocked_until};
+
+        let connection = self.pool.get()?;
+        connection.transaction::<_, AppError, _>(|| {
+            diesel::delete(login_attempts::table
+                .filter(last_failure_at.lt(now - Duration::seconds(policy.window)))
+                .filter(blocked_until.is_null().or(blocked_until.lt(now))))
+                .execute(&connection)?;
+
+            // Locking the row makes attempts at the same time wait for each
+            // other, so every one of them is counted.
+            let previous: Option<Attempts> = login_attempts::table.find(key)
+                .for_update()
+                .first(&connection)
+                .optional()?;
+            if let Some(seconds) = previous.as_ref().and_then(|a| a.retry_after(now)) {
+                return Err(AppError::TooManyRequests(seconds));
+            }
+            let next = policy.after_failure(key, previous, now);
+
+            diesel::insert_into(login_attempts::table)
+                .values(&next)
+                .on_conflict(login_attempts::key)
+                .do_update()
+                .set(&next)
+                .execute(&connection)?;
+            Ok(next)
+        })
+    }
+
+    fn refund(&self, key: &str, policy: &Policy) -> Result<(), AppError> {
+        let connection = self.pool.get()?;
+        connection.transaction::<_, AppError, _>(|| {
+            let current: Option<Attempts> = login_attempts::table.find(key)
+                .for_update()
+                .first(&connection)
+                .optional()?;
+            match current.and_then(|a| policy.after_refund(a)) {
+                Some(next) => {
+                    diesel::update(login_attempts::table.find(key)).set(&next).execute(&connection)?;
+                }
+                None => {
+                    diesel::delete(login_attempts::table.find(key)).execute(&connection)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn clear(&self, key: &str) -> Result<(), AppError> {
+        let connection = self.pool.get()?;
+        diesel::delete(login_attempts::table.find(key)).execute(&connection)?;
+        Ok(())
+    }
+}
+
+// What process_login talks to. It's shared by every worker through the App's
+// data, so the in memory counts are the same whichever worker gets the request.
+pub struct LoginThrottle {
+    store: Box<dyn AttemptStore>,
+    user: Policy,
+    ip: Policy,
+}
+
+impl LoginThrottle {
+    pub fn new(config: &ThrottleConfig, pool: Pool) -> Self {
+        let store: Box<dyn AttemptStore> = match config.store {
+            ThrottleStore::Memory => Box::new(MemoryStore::default()),
+            ThrottleStore::Postgres => Box::new(PostgresStore { pool }),
+        };
+        let user = Policy {
+            free_attempts: config.free_attempts,
+            lockout_after: config.lockout_after,
+            lockout_seconds: config.lockout_seconds,
+            window: config.window,
+        };
+        let ip = Policy {
+            free_attempts: config.free_attempts * IP_MULTIPLIER,
+            lockout_after: config.lockout_after * IP_MULTIPLIER,
+            ..user
+        };
+        LoginThrottle { store, user, ip }
+    }
+
+    fn keys(username: &str, ip: Option<&str>) -> (String, Option<String>) {
+        (format!("user:{}", username.to_lowercase()), ip.map(|ip| format!("ip:{}", ip)))
+    }
+
+    // Counts an attempt to get the password right against the username and
+    // the address, or turns it away with a 429 if either still has to wait.
+    // It's counted as a failure until it's handed back to succeeded() or
+    // refund().
+    pub fn attempt(&self, username: &str, ip: Option<&str>, now: NaiveDateTime) -> Result<Attempt, AppError> {
+        let (user_key, ip_key) = Self::keys(username, ip);
+
+        let attempts = self.store.record_attempt(&user_key, &self.user, now)?;
+        if attempts.failures == self.user.lockout_after {
+            log::warn!("Locked out {} after {} failed logins.", user_key, attempts.failures);
+        }
+        if let Some(ip_key) = &ip_key {
+            match self.store.record_attempt(ip_key, &self.ip, now) {
+                Ok(attempts) => if attempts.failures == self.ip.lockout_after {
+                    log::warn!("Locked out {} after {} failed logins.", ip_key, attempts.failures);
+                },
+                // The address has to wait, so the username shouldn't be
+                // charged for an attempt that never happened.
+                Err(e) => {
+                    self.store.refund(&user_key, &self.user)?;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(Attempt { user_key, ip_key })
+    }
+
+    // The password was right. The username starts over, the address only
+    // gets this attempt back.
+    pub fn succeeded(&self, attempt: Attempt) -> Result<(), AppError> {
+        self.store.clear(&attempt.user_key)?;
+        if let Some(ip_key) = &attempt.ip_key {
+            self.store.refund(ip_key, &self.ip)?;
+        }
+        Ok(())
+    }
+
+    // Takes the attempt back without clearing anything, for when the password
+    // was right but there's still a two-factor code to come.
+    pub fn refund(&self, attempt: Attempt) -> Result<(), AppError> {
+        self.store.refund(&attempt.user_key, &self.user)?;
+        if let Some(ip_key) = &attempt.ip_key {
+            self.store.refund(ip_key, &self.ip)?;
+        }
+        Ok(())
+    }
+}
+
+// An attempt that has been counted as a failure. Dropping it leaves it that
+// way.
+pub struct Attempt {
+    user_key: String,
+    ip_key: Option<String>,
+}
@@ -0,0 +1,161 @@
+// Two-factor authentication with time based one-time passwords (RFC 6238),
+// the six digit codes that authenticator apps show and change every 30
+// seconds. It's optional, users turn it on from their account page.
+//
+// Turning it on makes up a secret and shows it as a QR code of its otpauth://
+// URI for the app to scan. The secret is kept in users.totp_secret, but it
+// doesn't count until the user has typed in a code from the app, which sets
+// totp_enabled_at. Then they get a set of recovery codes to write down for
+// when they lose their phone. Each of those works once and we only keep their
+// hashes, like API tokens.
+//
+// With it on, the right password isn't enough for process_login. Instead of a
+// session the browser gets a short lived signed cookie saying who got their
+// password right, and has to bring it to /login/two-factor along with a code
+// before they're logged in. Wrong codes count against the login throttle just
+// like wrong passwords, six digits don't take long to guess otherwise.
+use actix_web::cookie::{Cookie, CookieJar, Key};
+use actix_web::{HttpMessage, HttpRequest};
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use sha1::Sha1;
+use super::config::CookieConfig;
+
+type HmacSha1 = Hmac<Sha1>;
+
+pub const DIGITS: u32 = 6;
+// How many seconds each code lasts.
+pub const STEP: i64 = 30;
+// Codes from the step before and after the current one are let in as well,
+// for phones whose clocks are a little out and people who type slowly.
+const SKEW: i64 = 1;
+// 160 bits, the length of a SHA-1 HMAC, as RFC 4226 recommends.
+const SECRET_BYTES: usize = 20;
+// What the authenticator app lists the account under.
+const ISSUER: &str = "The Oasis";
+
+pub const RECOVERY_CODES: usize = 10;
+// Without 0, o, 1, l and i, which are easy to mix up when copying by hand.
+const RECOVERY_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+const PENDING_COOKIE: &str = "login-two-factor";
+// How many seconds there are to type the code after getting the password right.
+pub const PENDING_LIFETIME: i64 = 300;
+
+// A new random secret, base32 encoded the way authenticator apps want it.
+pub fn new_secret() -> String {
+    let secret: [u8; SECRET_BYTES] = rand::thread_rng().gen();
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, &secret)
+}
+
+// The code for one 30 second step, the HOTP of RFC 4226 with the step number
+// as the counter.
+pub fn code_at(secret: &[u8], step: i64) -> u32 {
+    let mut mac = HmacSha1::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&(step as u64).to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // The last four bits pick which four bytes of the hash make the code.
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+// The step the code belongs to, if it's right for now. A code can't be used
+// twice, so codes from last_step, the step of the last code that got someone
+// in, and from before it aren't accepted.
+pub fn verify(secret: &str, code: &str, now: i64, last_step: Option<i64>) -> Option<i64> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let secret = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)?;
+
+    let current = now.div_euclid(STEP);
+    (current - SKEW..=current + SKEW)
+        .filter(|step| last_step.map_or(true, |last| *step > last))
+        .find(|step| code_at(&secret, *step) == code)
+}
+
+// The otpauth:// URI the QR code holds. Usernames are only ever letters,
+// numbers, - and _, so nothing in it needs escaping apart from the space in
+// the issuer.
+pub fn provisioning_uri(secret: &str, username: &str) -> String {
+    let issuer = ISSUER.replace(' ', "%20");
+    format!("otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            issuer, username, secret, issuer, DIGITS, STEP)
+}
+
+// The URI as an SVG QR code, to put straight into the page.
+pub fn qr_svg(uri: &str) -> Option<String> {
+    let code = qrcode::QrCode::new(uri.as_bytes()).ok()?;
+    Some(code.render::<qrcode::render::svg::Color>()
+        .min_dimensions(200, 200)
+        .build())
+}
+
+// A fresh set of recovery codes, like "k7q2m-xt9wd".
+pub fn new_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let chars: String = (0..10)
+                .map(|_| RECOVERY_ALPHABET[rng.gen_range(0, RECOVERY_ALPHABET.len())] as char)
+                .collect();
+            format!("{}-{}", &chars[..5], &chars[5..])
+        })
+        .collect()
+}
+
+// Recovery codes are hashed without the dash and in lower case, so they still
+// match however they're typed back in.
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+// The cookie that says this user got their password right and still has to
+// give a code. It's signed with the cookie key so nobody can write one for
+// someone else, and holds when it runs out as well as the user's id.
+pub fn pending_cookie(config: &CookieConfig, user_id: i32, now: i64) -> Cookie<'static> {
+    let mut jar = CookieJar::new();
+    jar.signed(&Key::derive_from(&config.key)).add(
+        Cookie::build(PENDING_COOKIE, format!("{}.{}", user_id, now + PENDING_LIFETIME))
+            .path("/")
+            .http_only(true)
+            .secure(config.secure)
+            .same_site(config.same_site)
+            .finish());
+    jar.get(PENDING_COOKIE).expect("just added").clone()
+}
+
+// The user the request's pending cookie is for, if it has one that we signed
+// and that hasn't run out.
+pub fn pending_user(req: &HttpRequest, config: &CookieConfig, now: i64) -> Option<i32> {
+    let cookie = req.cookie(PENDING_COOKIE)?;
+    let mut jar = CookieJar::new();
+    jar.add_original(cookie);
+    let value = jar.signed(&Key::derive_from(&config.key)).get(PENDING_COOKIE)?;
+
+    let (user_id, expires) = value.value().split_once('.')?;
+    let expires: i64 = expires.parse().ok()?;
+    if now >= expires {
+        return None;
+    }
+    user_id.parse().ok()
+}
+
+// For taking the pending cookie away again once the login is done.
+pub fn pending_removal() -> Cookie<'static> {
+    let mut cookie = Cookie::named(PENDING_COOKIE);
+    cookie.set_path("/");
+    cookie
+}
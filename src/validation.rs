@@ -0,0 +1,312 @@
+// Checks on what users type into our forms, before any of it gets near the
+// database. Each form lists the rules for its fields in its Validate impl.
+// When something is wrong the handler shows the same page again with the
+// message next to the field and what the user typed still filled in, and
+// answers with 422 Unprocessable Entity.
+use std::collections::BTreeMap;
+use serde::Serialize;
+use super::models::{NewUser, LoginUser};
+use super::search::{parse_date, SearchQuery};
+use super::accounts::Mode;
+use super::{CommentForm, DeleteAccountForm, DisableTwoFactorForm, EmailSettingsForm, ForgotPasswordForm, PostForm,
+            TokenForm, TwoFactorForm, UsernameSettingsForm};
+
+// What is wrong with each field, keyed by the field's name in the form. We only
+// keep the first problem with a field, fixing that one at a time is easier
+// than reading a list.
+#[derive(Debug, Default, Serialize)]
+pub struct Errors(BTreeMap<&'static str, String>);
+
+impl Errors {
+    pub fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        self.0.entry(field).or_insert_with(|| message.into());
+    }
+
+    pub fn get(&self, field: &str) -> Option<&str> {
+        self.0.get(field).map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn into_result(self) -> Result<(), Errors> {
+        if self.is_empty() { Ok(()) } else { Err(self) }
+    }
+}
+
+pub trait Validate {
+    fn validate(&self) -> Result<(), Errors>;
+}
+
+pub const USERNAME_LENGTH: (usize, usize) = (3, 32);
+pub const PASSWORD_LENGTH: (usize, usize) = (8, 128);
+pub const TITLE_LENGTH: (usize, usize) = (1, 300);
+pub const BODY_LENGTH: (usize, usize) = (1, 40_000);
+pub const COMMENT_LENGTH: (usize, usize) = (1, 10_000);
+pub const TOKEN_NAME_LENGTH: (usize, usize) = (1, 64);
+
+impl Validate for NewUser {
+    fn validate(&self) -> Result<(), Errors> {
+        let mut errors = Errors::default();
+        username(&mut errors, "username", &self.username);
+        email(&mut errors, "email", &self.email);
+        password(&mut errors, "password", &self.password, &self.username);
+        errors.into_result()
+    }
+}
+
+// Logging in only checks that something was typed, whether it's right is up
+// to the password check.
+impl Validate for LoginUser {
+    fn validate(&self) -> Result<(), Errors> {
+        let mut errors = Errors::default();
+        required(&mut errors, "username", &self.username);
+        required(&mut errors, "password", &self.password);
+        errors.into_result()
+    }
+}
+
+// The link and the body are each optional, but a post with neither has
+// nothing in it.
+impl Validate for PostForm {
+    fn validate(&self) -> Result<(), Errors> {
+        let mut errors = Errors::default();
+        length(&mut errors, "title", self.title.trim(), TITLE_LENGTH);
+
+        let has_link = !self.link.trim().is_empty();
+        let has_body = !self.body.trim().is_empty();
+        if has_link {
+            link(&mut errors, "link", &self.link);
+        }
+        if has_body {
+            length(&mut errors, "body", self.body.trim(), BODY_LENGTH);
+        }
+        if !has_link && !has_body {
+            errors.add("link", "Add a link, some text, or both.");
+        }
+        errors.into_result()
+    }
+}
+
+impl Validate for CommentForm {
+    fn validate(&self) -> Result<(), Errors> {
+        let mut errors = Errors::default();
+        length(&mut errors, "comment", self.comment.trim(), COMMENT_LENGTH);
+        errors.into_result()
+    }
+}
+
+// A token without any scopes couldn't do anything at all.
+impl Validate for TokenForm {
+    fn validate(&self) -> Result<(), Errors> {
+        let mut errors = Errors::default();
+        length(&mut errors, "name", self.name.trim(), TOKEN_NAME_LENGTH);
+        if self.scopes().is_empty() {
+            errors.add("scopes", "Pick at least one thing the token can do.");
+        }
+        errors.into_result()
+    }
+}
+
+impl Validate for ForgotPasswordForm {
+    fn validate(&self) -> Result<(), Errors> {
+        let mut errors = Errors::default();
+        email(&mut errors, "email", self.email.trim());
+        errors.into_result()
+    }
+}
+
+// Whether the code is right is up to twofactor.rs.
+impl Validate for TwoFactorForm {
+    fn validate(&self) -> Result<(), Errors> {
+        let mut errors = Errors::default();
+        required(&mut errors, "code", &self.code);
+        errors.into_result()
+    }
+}
+
+impl Validate for DisableTwoFactorForm {
+    fn validate(&self) -> Result<(), Errors> {
+        let mut errors = Errors::default();
+        required(&mut errors, "password", &self.password);
+        errors.into_result()
+    }
+}
+
+// A new password for an existing user, typed twice. The same rules apply as
+// when signing up.
+pub struct NewPassword<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+    pub confirm: &'a str,
+}
+
+impl Validate for NewPassword<'_> {
+    fn validate(&self) -> Result<(), Errors> {
+        let mut errors = Errors::default();
+        password(&mut errors, "password", self.password, self.username);
+        if self.password != self.confirm {
+            errors.add("confirm", "The passwords don't match.");
+        }
+        errors.into_result()
+    }
+}
+
+// Changing the password from the settings page. The new one follows the same
+// rules as when signing up, whether the current one is right is checked
+// afterwards.
+pub struct PasswordChange<'a> {
+    pub username: &'a str,
+    pub current_password: &'a str,
+    pub password: &'a str,
+    pub confirm: &'a str,
+}
+
+impl Validate for PasswordChange<'_> {
+    fn validate(&self) -> Result<(), Errors> {
+        let mut errors = Errors::default();
+        required(&mut errors, "current_password", self.current_password);
+        password(&mut errors, "password", self.password, self.username);
+        if self.password != self.confirm {
+            errors.add("confirm", "The passwords don't match.");
+        }
+        errors.into_result()
+    }
+}
+
+impl Validate for EmailSettingsForm {
+    fn validate(&self) -> Result<(), Errors> {
+        let mut errors = Errors::default();
+        email(&mut errors, "email", self.email.trim());
+        required(&mut errors, "password", &self.password);
+        errors.into_result()
+    }
+}
+
+impl Validate for UsernameSettingsForm {
+    fn validate(&self) -> Result<(), Errors> {
+        let mut errors = Errors::default();
+        username(&mut errors, "username", &self.username);
+        errors.into_result()
+    }
+}
+
+// Deleting an account hands back the mode that was picked when everything is
+// fine, so the handler doesn't have to parse it a second time.
+impl DeleteAccountForm {
+    pub fn validated_mode(&self) -> Result<Mode, Errors> {
+        let mut errors = Errors::default();
+        let mode = Mode::parse(&self.mode);
+        if mode.is_none() {
+            errors.add("mode", "Choose what happens to your posts and comments.");
+        }
+        required(&mut errors, "password", &self.password);
+        match mode {
+            Some(mode) if errors.is_empty() => Ok(mode),
+            _ => Err(errors),
+        }
+    }
+}
+
+// An empty search is fine, it just shows the form. The dates have to be
+// real dates, in the right order.
+impl Validate for SearchQuery {
+    fn validate(&self) -> Result<(), Errors> {
+        let mut errors = Errors::default();
+        date(&mut errors, "from", &self.from);
+        date(&mut errors, "to", &self.to);
+        if let (Some(from), Some(to)) = (self.from_date(), self.to_date()) {
+            if from > to {
+                errors.add("to", "This has to be on or after the from date.");
+            }
+        }
+        errors.into_result()
+    }
+}
+
+fn required(errors: &mut Errors, field: &'static str, value: &str) {
+    if value.trim().is_empty() {
+        errors.add(field, "This can't be blank.");
+    }
+}
+
+// Lengths are counted in characters rather than bytes, so an accented letter
+// counts as one.
+fn length(errors: &mut Errors, field: &'static str, value: &str, (min, max): (usize, usize)) {
+    let count = value.chars().count();
+    if count == 0 {
+        errors.add(field, "This can't be blank.");
+    } else if count < min {
+        errors.add(field, format!("This needs to be at least {} characters.", min));
+    } else if count > max {
+        errors.add(field, format!("This can be at most {} characters.", max));
+    }
+}
+
+// Usernames end up in urls (/user/{username}), so we stick to characters that
+// never need escaping there.
+fn username(errors: &mut Errors, field: &'static str, value: &str) {
+    length(errors, field, value, USERNAME_LENGTH);
+    if !value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        errors.add(field, "Use only letters, numbers, - and _.");
+    }
+}
+
+// Proper email validation is famously hard, and the only real test is sending
+// a message to it. This catches typos like a missing @ or domain.
+fn email(errors: &mut Errors, field: &'static str, value: &str) {
+    if value.trim().is_empty() {
+        errors.add(field, "This can't be blank.");
+        return;
+    }
+
+    let valid = match value.split_once('@') {
+        Some((local, domain)) => !local.is_empty()
+            && !domain.contains('@')
+            && domain.contains('.')
+            && !domain.starts_with('.')
+            && !domain.ends_with('.'),
+        None => false,
+    };
+
+    if !valid || value.len() > 254 || value.chars().any(char::is_whitespace) {
+        errors.add(field, "That doesn't look like an email address.");
+    }
+}
+
+// Long enough, not just one kind of character, and not the username.
+fn password(errors: &mut Errors, field: &'static str, value: &str, username: &str) {
+    length(errors, field, value, PASSWORD_LENGTH);
+
+    let kinds = [
+        value.chars().any(|c| c.is_lowercase()),
+        value.chars().any(|c| c.is_uppercase()),
+        value.chars().any(|c| c.is_numeric()),
+        value.chars().any(|c| !c.is_alphanumeric()),
+    ].iter().filter(|&&k| k).count();
+
+    if kinds < 2 {
+        errors.add(field, "Mix in some capitals, numbers or symbols.");
+    }
+    if !username.is_empty() && value.to_lowercase().contains(&username.to_lowercase()) {
+        errors.add(field, "Your password can't contain your username.");
+    }
+}
+
+// Links have to go somewhere on the web. Anything else, javascript: urls in
+// particular, could run code in the browser of whoever clicks them.
+fn link(errors: &mut Errors, field: &'static str, value: &str) {
+    match url::Url::parse(value.trim()) {
+        Ok(url) if (url.scheme() == "http" || url.scheme() == "https") && url.has_host() => {}
+        Ok(_) => errors.add(field, "Links have to start with http:// or https://."),
+        Err(_) if value.trim().is_empty() => errors.add(field, "This can't be blank."),
+        Err(_) => errors.add(field, "That doesn't look like a link."),
+    }
+}
+
+fn date(errors: &mut Errors, field: &'static str, value: &str) {
+    if !value.trim().is_empty() && parse_date(value).is_none() {
+        errors.add(field, "Dates look like 2021-05-31.");
+    }
+}
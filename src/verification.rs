@@ -0,0 +1,123 @@
+// Checking that people own the email address they signed up with. New
+// accounts start out unverified and we email them a link, which marks the
+// account as verified when they follow it.
+//
+// Nothing about the link is stored. It carries the user's id and when it
+// expires, signed with an HMAC over those and the email address so nobody can
+// make one up. Signing the address too means a link stops working if the
+// address is changed before it's used. The HMAC key is made from the cookie
+// key, which is already the secret that vouches for who people are, so there
+// isn't another one to set up, but it's never the cookie key itself. After the
+// cookie key is rotated, links signed with the old one keep working until its
+// grace period is over, like the login cookies do.
+//
+// Until they verify, what users can do is limited by the UNVERIFIED_CAN_*
+// settings, see check().
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use tera::{Context, Tera};
+use super::auth::Scope;
+use super::config::{Config, CookieConfig};
+use super::errors::AppError;
+use super::mailer::{Email, Mailer};
+use super::models::User;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// The parts of a verification link's token, before the signature is checked.
+#[derive(Debug, PartialEq)]
+pub struct Token {
+    pub user_id: i32,
+    pub expires: i64,
+    signature: Vec<u8>,
+}
+
+// The key links are signed with, for the given cookie key.
+pub fn link_key(cookie_key: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(cookie_key).expect("HMAC takes keys of any length");
+    mac.update(b"email verification links");
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn mac(key: &[u8], user_id: i32, email: &str, expires: i64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(format!("verify-email:{}:{}:{}", user_id, email.to_lowercase(), expires).as_bytes());
+    mac
+}
+
+// The token for the link in the email: user id, expiry as a unix timestamp
+// and the signature, separated by dots.
+pub fn sign(key: &[u8], user: &User, expires: i64) -> String {
+    let signature = mac(key, user.id, &user.email, expires).finalize().into_bytes();
+    format!("{}.{}.{}", user.id, expires, hex::encode(signature))
+}
+
+impl Token {
+    pub fn parse(token: &str) -> Option<Token> {
+        let mut parts = token.splitn(3, '.');
+        Some(Token {
+            user_id: parts.next()?.parse().ok()?,
+            expires: parts.next()?.parse().ok()?,
+            signature: hex::decode(parts.next()?).ok()?,
+        })
+    }
+
+    // Whether this token was signed by us for this user's current address and
+    // hasn't expired. The comparison takes the same time however much of the
+    // signature is right.
+    pub fn is_valid_for(&self, key: &[u8], user: &User, now: i64) -> bool {
+        user.id == self.user_id
+            && now < self.expires
+            && mac(key, user.id, &user.email, self.expires).verify(&self.signature).is_ok()
+    }
+
+    // is_valid_for with every link key that's good right now: the one from
+    // the current cookie key, and the previous cookie key's while it lasts.
+    pub fn is_valid(&self, config: &CookieConfig, user: &User, now: chrono::NaiveDateTime) -> bool {
+        let previous = match (&config.previous_key, config.previous_key_until) {
+            (Some(key), Some(until)) if now < until => Some(key),
+            _ => None,
+        };
+        std::iter::once(&config.key)
+            .chain(previous)
+            .any(|key| self.is_valid_for(&link_key(key), user, now.timestamp()))
+    }
+}
+
+// Emails the user a fresh link. This talks to the mail server, so it has to
+// run inside web::block.
+pub fn send(mailer: &dyn Mailer, tera: &Tera, config: &Config, user: &User) -> Result<(), AppError> {
+    let expires = chrono::Utc::now().timestamp() + config.verify_link_lifetime;
+    let token = sign(&link_key(&config.cookie.key), user, expires);
+
+    let mut data = Context::new();
+    data.insert("username", &user.username);
+    data.insert("link", &format!("{}/verify-email/{}", config.site_url, token));
+    data.insert("expires_in", &super::duration_in_words(config.verify_link_lifetime));
+
+    mailer.send(&Email {
+        to: user.email.clone(),
+        subject: "Verify your email address on The Oasis".to_string(),
+        body: tera.render("emails/verify_email.txt", &data)?,
+    })?;
+    Ok(())
+}
+
+// Refuses what the settings don't let unverified users do yet.
+pub fn check(user: &User, scope: Scope, config: &Config) -> Result<(), AppError> {
+    let allowed = match scope {
+        // Voting has never needed a verified address.
+        Scope::Read | Scope::Vote => true,
+        Scope::Post => config.unverified_can_post,
+        Scope::Comment => config.unverified_can_comment,
+    };
+
+    if user.is_verified() || allowed {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden(format!(
+            "Verify your email address before you {}. Follow the link we emailed you, \
+             or get a new one from your account page.",
+            scope.action())))
+    }
+}